@@ -5,6 +5,10 @@ cd "$(dirname $0)"
 
 echo Target set to $TARGET
 
+# the program id is the package name, as declared by the canister
+PROGRAM_ID=$(sed -n 's/^name *= *"\(.*\)"/\1/p' Cargo.toml | head -n 1)
+WASM=$TARGET/wasm32-unknown-unknown/release/${PROGRAM_ID//-/_}.wasm
+
 # create assets path in current working directory
 mkdir -p ~/.config/harness
 
@@ -13,14 +17,26 @@ cargo build --features __harness-build --target wasm32-unknown-unknown --release
 
 # then we optimize the wasm for size & send the output file to a normalized destination with a known name
 cargo install wasm-opt
-wasm-opt -Oz --strip-debug -o ~/.config/harness/harness_code.wasm $TARGET/wasm32-unknown-unknown/release/hello.wasm
+wasm-opt -Oz --strip-debug -o ~/.config/harness/harness_code.wasm $WASM
+
+# with a signing key, an ed25519 private key in PEM, we sign a manifest of the program that the
+# canister serves next to it
+MANIFEST=~/.config/harness/harness_manifest
+rm -f $MANIFEST.json $MANIFEST.pub $MANIFEST.sig
+if [ -n "$HARNESS_SIGNING_KEY" ]; then
+    HASH=$(sha256sum ~/.config/harness/harness_code.wasm | cut -d ' ' -f 1)
+    VERSION=$(cargo pkgid | sed 's/.*[#@]//')
+    printf '{"program_id":"%s","version":"%s","sha256":"%s"}' "$PROGRAM_ID" "$VERSION" "$HASH" > $MANIFEST.json
+    openssl pkeyutl -sign -inkey "$HARNESS_SIGNING_KEY" -rawin -in $MANIFEST.json -out $MANIFEST.sig
+    openssl pkey -in "$HARNESS_SIGNING_KEY" -pubout -outform DER | tail -c 32 > $MANIFEST.pub
+fi
 
 # we can have our second pass to build the final wasm
 cargo build --target wasm32-unknown-unknown --release
 
 # we can now generate the did file, piping the output to a file
 cargo install candid-extractor
-candid-extractor $TARGET/wasm32-unknown-unknown/release/hello.wasm > ./src/hello.did
+candid-extractor $WASM > ./src/hello.did
 
 # using dfx we can now try deploy on out local network
 dfx stop
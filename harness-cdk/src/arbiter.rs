@@ -1,15 +1,20 @@
 //! This is is where the harness program is loaded at compile time, we create the arbiter to arbiter operations of the harness program.
 use std::cell::{Cell, RefCell};
 
-use harness_macros::get_binary__;
+use candid::Principal;
+use ic_cdk::api::management_canister::main::raw_rand;
+
+use harness_macros::{get_binary__, get_binary_hash__, get_manifest__};
 use harness_primitives::{
+    auth::{encode_secret, SECRET_SIZE},
     error::{Error, Result},
+    manifest::SignedManifest,
     program::Program,
 };
 
 struct Arbiter {
-    // The collection of device urls that have been registered with the arbiter.
-    devices: Vec<String>,
+    // The collection of devices that have been registered with the arbiter.
+    devices: Vec<Device>,
     // The harness program that is loaded into the arbiter at compile time.
     program: Program,
 }
@@ -24,19 +29,86 @@ thread_local! {
     })};
 }
 
+/// A device registered with the arbiter along with the secret the calls to it are signed with.
+#[derive(Clone)]
+pub struct Device {
+    pub url: String,
+    pub secret: Vec<u8>,
+    /// The principal that registered the device, the only one that may renew or remove it.
+    pub owner: Principal,
+}
+
 /// This is redirection that does not expose the ARBITER to the user.
 pub struct StateAccessor;
 
 impl StateAccessor {
-    pub fn add_device(url: String) {
-        ARBITER.with(|arbiter| arbiter.borrow_mut().devices.push(url));
+    /// Registers the device of the caller with a secret drawn from the IC randomness, returned hex
+    /// encoded. A device registered by another principal is refused.
+    pub async fn register_device(url: String) -> Result<String> {
+        let owner = ic_cdk::caller();
+        if owner == Principal::anonymous() {
+            return Err(Error::Unauthorized {
+                message: "devices cannot be registered anonymously".to_string(),
+            });
+        }
+        Self::check_owner(&url, owner)?;
+        let (secret,) = raw_rand()
+            .await
+            .expect("the management canister provides randomness; qed");
+        let secret = secret[..SECRET_SIZE].to_vec();
+        let encoded = encode_secret(&secret);
+        Self::add_device(url, secret, owner)?;
+        Ok(encoded)
+    }
+
+    /// Registers the device, the owner registering it again is issued the new secret.
+    pub fn add_device(url: String, secret: Vec<u8>, owner: Principal) -> Result<()> {
+        Self::check_owner(&url, owner)?;
+        ARBITER.with(|arbiter| {
+            let devices = &mut arbiter.borrow_mut().devices;
+            devices.retain(|device| device.url != url);
+            devices.push(Device { url, secret, owner });
+        });
+        Ok(())
+    }
+
+    /// Refuses the principal when the device is registered by another one.
+    fn check_owner(url: &str, owner: Principal) -> Result<()> {
+        ARBITER.with(|arbiter| {
+            match arbiter
+                .borrow()
+                .devices
+                .iter()
+                .find(|device| device.url == url)
+            {
+                Some(device) if device.owner != owner => Err(Error::Unauthorized {
+                    message: format!("the device {url} is registered by another principal"),
+                }),
+                _ => Ok(()),
+            }
+        })
     }
 
     pub fn get_program_code() -> Vec<u8> {
         ARBITER.with(|arbiter| arbiter.borrow().program.0.to_vec())
     }
 
-    pub fn get_next_device() -> Result<String> {
+    /// The hex encoded SHA-256 hash of the program code, computed when the canister is built.
+    pub fn get_program_hash() -> String {
+        get_binary_hash__!().to_string()
+    }
+
+    /// The manifest signed by the program owner when the canister was built, if any.
+    pub fn get_program_manifest() -> Option<SignedManifest> {
+        let manifest: Option<(&[u8], &[u8], &[u8])> = get_manifest__!();
+        manifest.map(|(manifest, public_key, signature)| SignedManifest {
+            manifest: manifest.to_vec(),
+            public_key: public_key.to_vec(),
+            signature: signature.to_vec(),
+        })
+    }
+
+    pub fn get_next_device() -> Result<Device> {
         ARBITER.with(|arbiter| {
             let devices = &arbiter.borrow().devices;
             if devices.is_empty() {
@@ -57,15 +129,25 @@ impl StateAccessor {
     }
 
     pub fn get_devices() -> Vec<String> {
-        ARBITER.with(|arbiter| arbiter.borrow().devices.clone())
+        ARBITER.with(|arbiter| {
+            arbiter
+                .borrow()
+                .devices
+                .iter()
+                .map(|device| device.url.clone())
+                .collect()
+        })
     }
 
-    pub fn remove_device(url: String) {
+    /// Removes the device of the caller, a device registered by another principal is refused.
+    pub fn remove_device(url: String) -> Result<()> {
+        Self::check_owner(&url, ic_cdk::caller())?;
         ARBITER.with(|arbiter| {
             let devices = &mut arbiter.borrow_mut().devices;
-            if let Some(idx) = devices.iter().position(|x| x == &url) {
+            if let Some(idx) = devices.iter().position(|device| device.url == url) {
                 devices.remove(idx);
             }
         });
+        Ok(())
     }
 }
@@ -14,7 +14,9 @@ pub mod prelude {
     #[cfg(feature = "__harness-build")]
     pub use wapc_guest::{self, register_function, CallResult};
 
-    pub use harness_macros::{get_binary__, harness, harness_export__};
+    pub use harness_macros::{
+        get_binary__, get_binary_hash__, get_manifest__, harness, harness_export__,
+    };
     pub use harness_primitives;
 
     pub use crate::arbiter::StateAccessor;
@@ -27,11 +29,14 @@ macro_rules! harness_export {
     () => {
         harness_export__!();
 
-        // There is no security done here, research to be done on how to prevent bad actors from registering devices
+        // The device is issued the hex encoded secret the calls to it are signed with, registering again replaces it.
+        // A device belongs to the principal that registered it, the call of any other principal is rejected.
         #[cfg(not(feature = "__harness-build"))]
         #[update]
-        fn register_device(url: String) {
-            StateAccessor::add_device(url)
+        async fn register_device(url: String) -> String {
+            StateAccessor::register_device(url)
+                .await
+                .unwrap_or_else(|err| ic_cdk::trap(&err.to_string()))
         }
 
         // Allows the user to retrieve the program code of the harness program.
@@ -41,6 +46,20 @@ macro_rules! harness_export {
             StateAccessor::get_program_code()
         }
 
+        // Allows devices to verify the program code they pulled.
+        #[cfg(not(feature = "__harness-build"))]
+        #[query]
+        fn get_program_hash() -> String {
+            StateAccessor::get_program_hash()
+        }
+
+        // Allows devices to verify who published the program code.
+        #[cfg(not(feature = "__harness-build"))]
+        #[query]
+        fn get_program_manifest() -> Option<harness_primitives::manifest::SignedManifest> {
+            StateAccessor::get_program_manifest()
+        }
+
         // Allows the user to get the list of devices that have been registered with the arbiter.
         // there should be a way to filter if user has permissions which is not a priority at the moment.
         #[cfg(not(feature = "__harness-build"))]
@@ -49,10 +68,11 @@ macro_rules! harness_export {
             StateAccessor::get_devices()
         }
 
+        // Only the principal that registered the device may remove it.
         #[cfg(not(feature = "__harness-build"))]
         #[update]
         fn remove_device(url: String) {
-            StateAccessor::remove_device(url)
+            StateAccessor::remove_device(url).unwrap_or_else(|err| ic_cdk::trap(&err.to_string()))
         }
 
         // Copied over from example `send_http_post_rust`
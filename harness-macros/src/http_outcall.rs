@@ -46,8 +46,7 @@ pub fn impl_http_outcall(func: ItemFn) -> syn::Result<TokenStream> {
                 quote!(#type_path),
                 quote! {
                     {
-                        let resp = &response.body[2..];
-                        ::candid::Decode!(&resp, #type_path)
+                        ::candid::Decode!(&response.body, #type_path)
                         .expect("the response should implement CandidType; qed")
                     }
                 },
@@ -65,8 +64,8 @@ pub fn impl_http_outcall(func: ItemFn) -> syn::Result<TokenStream> {
     Ok(TokenStream::from(quote! {
         #[update]
         async fn #ident(#(#inputs),*) -> harness_primitives::HarnessResult<#output> {
-            let device_url = match StateAccessor::get_next_device() {
-                Ok(url) => url,
+            let device = match StateAccessor::get_next_device() {
+                Ok(device) => device,
                 Err(e) => return harness_primitives::HarnessResult::<#output>::wrap_error(e),
             };
 
@@ -78,23 +77,41 @@ pub fn impl_http_outcall(func: ItemFn) -> syn::Result<TokenStream> {
                 closing_price_index: 4,
             };
 
-            let body = Some(::candid::Encode!(&#(#args),*).expect("the data types should impl CandidType; qed"));
+            let body = ::candid::Encode!(&#(#args),*).expect("the data types should impl CandidType; qed");
+
+            // the time of the round is the same on every replica, so is the signature
+            // the canister id lets a device that has not pulled the program yet load it
+            let signed_headers = [program_id, String::from(#procedure), ic_cdk::api::id().to_text()];
+            let signed = harness_primitives::auth::SignedRequest {
+                method: "POST",
+                path: "/procedure",
+                headers: &signed_headers,
+                body: &body,
+                timestamp: ic_cdk::api::time(),
+            };
+            let mut headers = harness_primitives::auth::SIGNED_HEADERS
+                .iter()
+                .zip(signed_headers.iter())
+                .map(|(header, value)| HttpHeader {
+                    name: header.to_string(),
+                    value: value.clone(),
+                })
+                .collect::<Vec<_>>();
+            headers.push(HttpHeader {
+                name: harness_primitives::http::Header::Timestamp.to_string(),
+                value: signed.timestamp.to_string(),
+            });
+            headers.push(HttpHeader {
+                name: harness_primitives::http::Header::Signature.to_string(),
+                value: signed.sign(&device.secret),
+            });
 
             let request = CanisterHttpRequestArgument {
-                url: device_url + "/procedure",
+                url: device.url + "/procedure",
                 max_response_bytes: None,
                 method: HttpMethod::POST,
-                headers: vec![
-                    HttpHeader {
-                        name: harness_primitives::http::Header::ProgramId.to_string(),
-                        value: program_id,
-                    },
-                    HttpHeader {
-                        name: harness_primitives::http::Header::ProgramProc.to_string(),
-                        value: String::from(#procedure),
-                    },
-                ],
-                body,
+                headers,
+                body: Some(body),
                 transform: Some(TransformContext::from_name(
                     "harness_transform".to_string(),
                     serde_json::to_vec(&context).unwrap(),
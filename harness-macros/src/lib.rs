@@ -5,6 +5,7 @@ use std::sync::Mutex;
 use proc_macro::TokenStream;
 use proc_macro2::{Ident, Span};
 use quote::{quote, ToTokens};
+use sha2::{Digest, Sha256};
 use syn::{Error, ItemFn, Signature, Type};
 
 use harness_primitives::HARNESS_PATH;
@@ -12,13 +13,17 @@ use harness_primitives::HARNESS_PATH;
 mod http_outcall;
 
 /// Reserved method names that cannot be used as harness functions.
-const RESERVED_METHODS: [&str; 5] = [
+const RESERVED_METHODS: [&str; 7] = [
     // `wapc_init` is reserved by the wapc protocol used in the project.
     "wapc_init",
     // `register_function` is public API for registering harness nodes.
     "register_function",
     // `get_program_code` is a public API for getting the embedded harness program.
     "get_program_code",
+    // `get_program_hash` is a public API for getting the SHA-256 hash of the embedded program.
+    "get_program_hash",
+    // `get_program_manifest` is a public API for getting the signed manifest of the embedded program.
+    "get_program_manifest",
     // `get_devices` is a public API for getting the list of devices registered with the arbiter.
     "get_devices",
     // `remove_device` is a public API for registering devices to the arbiter.
@@ -247,18 +252,70 @@ fn get_args(sig: &Signature) -> syn::Result<(Vec<Type>, Vec<Type>)> {
 
 #[proc_macro]
 pub fn get_binary__(_item: TokenStream) -> TokenStream {
+    match read_binary() {
+        Ok(buffer) => TokenStream::from(quote! { &[#(#buffer),*] }),
+        Err(err) => err,
+    }
+}
+
+/// Expands to the hex encoded SHA-256 hash of the harness program embedded by `get_binary__`,
+/// published by the canister so that devices can verify the program they pull.
+#[proc_macro]
+pub fn get_binary_hash__(_item: TokenStream) -> TokenStream {
+    match read_binary() {
+        Ok(buffer) => {
+            let hash = hex::encode(Sha256::digest(&buffer));
+            TokenStream::from(quote! { #hash })
+        }
+        Err(err) => err,
+    }
+}
+
+/// Expands to the signed manifest of the harness program as the `(manifest, public_key, signature)`
+/// byte slices, or `None` when the program was not signed.
+///
+/// The manifest is read from `harness_manifest.json`, `harness_manifest.pub` and
+/// `harness_manifest.sig` next to the harness program, as written by the build script.
+#[proc_macro]
+pub fn get_manifest__(_item: TokenStream) -> TokenStream {
+    let dir = match std::path::Path::new(HARNESS_PATH).parent() {
+        Some(dir) => dir,
+        None => return TokenStream::from(quote!(None)),
+    };
+
+    let parts = [
+        "harness_manifest.json",
+        "harness_manifest.pub",
+        "harness_manifest.sig",
+    ]
+    .map(|file| std::fs::read(dir.join(file)).ok());
+    match parts {
+        [Some(manifest), Some(public_key), Some(signature)] if !manifest.is_empty() => {
+            TokenStream::from(quote! {
+                Some((
+                    &[#(#manifest),*] as &[u8],
+                    &[#(#public_key),*] as &[u8],
+                    &[#(#signature),*] as &[u8],
+                ))
+            })
+        }
+        _ => TokenStream::from(quote!(None)),
+    }
+}
+
+fn read_binary() -> Result<Vec<u8>, TokenStream> {
     // get harness compiled code
     let path = std::path::Path::new(HARNESS_PATH);
     let mut f = match std::fs::File::open(path) {
         Ok(val) => val,
         Err(_) => {
             if cfg!(not(feature = "__harness-build")) {
-                return syn::Error::new(Span::call_site(), "wasm file not found, please call after the first build with `--features __harness-build`")
+                return Err(syn::Error::new(Span::call_site(), "wasm file not found, please call after the first build with `--features __harness-build`")
                   .to_compile_error()
-                  .into();
+                  .into());
             }
 
-            return TokenStream::from(quote!(&[]));
+            return Ok(Vec::new());
         }
     };
 
@@ -266,5 +323,5 @@ pub fn get_binary__(_item: TokenStream) -> TokenStream {
     f.read_to_end(&mut buffer)
         .expect("file read to succeed; qed");
 
-    TokenStream::from(quote! { &[#(#buffer),*] })
+    Ok(buffer)
 }
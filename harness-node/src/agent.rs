@@ -0,0 +1,383 @@
+//! The calls the node makes to the harness canisters, through the ICP agent.
+//!
+//! Every failure is a harness [`Error`]: a malformed canister id or replica URL is the caller's
+//! mistake and answers `400`, a replica that cannot be reached or a canister that rejects the
+//! call or answers something unexpected answers `502`.
+//!
+//! Replies are verified with the root key of the canister's [`Network`]: the IC root key pinned
+//! in the agent on mainnet, the configured key on a custom network. Only on a local network is
+//! the root key fetched from the replica, which is then trusted blindly.
+//!
+//! The node keeps one agent per replica URL and network, created on the first call through it,
+//! and calls with the identity from [`crate::identity`].
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use candid::{utils::ArgumentEncoder, CandidType, Decode};
+use ic_agent::{export::Principal, Agent, AgentError, Identity};
+use serde::de::DeserializeOwned;
+use tokio::sync::OnceCell;
+use url::{Host, Url};
+
+use harness_primitives::{
+    error::{Error, Result as HarnessResult},
+    internals::Schema,
+    manifest::SignedManifest,
+    program::ProgramId,
+};
+
+use crate::{
+    config::{Config, Network},
+    identity,
+};
+
+/// This is the interface for the ICP agent that is used to poll the IC canister.
+pub trait IcpAgent {
+    /// The code of the program embedded in the canister.
+    fn get_program_code(
+        &self,
+        canister_id: &str,
+        icp_url: &str,
+    ) -> impl Future<Output = HarnessResult<Vec<u8>>> + Send;
+
+    /// The hex encoded SHA-256 hash of the program code, as published by the canister.
+    fn get_program_hash(
+        &self,
+        canister_id: &str,
+        icp_url: &str,
+    ) -> impl Future<Output = HarnessResult<String>> + Send;
+
+    /// The manifest signed by the program owner, `None` when the program is not signed.
+    fn get_program_manifest(
+        &self,
+        canister_id: &str,
+        icp_url: &str,
+    ) -> impl Future<Output = HarnessResult<Option<SignedManifest>>> + Send;
+
+    /// The schema of the program, its name, version and the procedures it serves.
+    fn get_schema(
+        &self,
+        canister_id: &str,
+        icp_url: &str,
+    ) -> impl Future<Output = HarnessResult<Schema>> + Send;
+
+    /// The id the canister calls its program by, in the `Program-Identifier` of its calls.
+    fn get_program_id(
+        &self,
+        canister_id: &str,
+        icp_url: &str,
+    ) -> impl Future<Output = HarnessResult<ProgramId>> + Send;
+
+    /// Registers the device reachable at `device_url` with the canister, returning the hex
+    /// encoded secret the canister signs its calls with. Registering again issues a new secret.
+    fn register_device(
+        &self,
+        canister_id: &str,
+        icp_url: &str,
+        device_url: &str,
+    ) -> impl Future<Output = HarnessResult<String>> + Send;
+
+    /// Deregisters the device reachable at `device_url` from the canister.
+    fn remove_device(
+        &self,
+        canister_id: &str,
+        icp_url: &str,
+        device_url: &str,
+    ) -> impl Future<Output = HarnessResult<()>> + Send;
+}
+
+/// This is the implementation of the ICP agent, reaching each canister on its configured
+/// network with the identity of the node.
+pub struct IcpAgentImpl {
+    identity: Arc<dyn Identity>,
+    principal: Principal,
+    root_key: RootKey,
+    canisters: HashMap<String, RootKey>,
+    agents: Mutex<HashMap<(String, RootKey), AgentCell>>,
+}
+
+/// The agent of a replica URL and network, created once.
+type AgentCell = Arc<OnceCell<Agent>>;
+
+/// Where the root key the replies are verified with comes from.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+enum RootKey {
+    /// Fetched from the replica, on a local network.
+    Fetched,
+    /// The IC root key pinned in the agent.
+    #[default]
+    Mainnet,
+    /// The DER encoded key of a custom network.
+    Pinned(Vec<u8>),
+}
+
+impl IcpAgentImpl {
+    /// Creates the agent reaching the canisters on the networks of the config, the canisters
+    /// that are not configured are reached on the node wide network. The node identity is read
+    /// from the configured PEM file, or generated in the data directory on first start.
+    pub fn new(config: &Config) -> HarnessResult<Self> {
+        let (identity_pem, generate) = config.identity_pem();
+        let identity = identity::load(&identity_pem, generate)?;
+        let principal = identity.sender().map_err(|message| Error::Internal {
+            message: format!("failed to derive the principal of the node identity: {message}"),
+            inner: None,
+        })?;
+
+        let root_key = |network: Network, root_key: Option<&str>| match network {
+            Network::Local => Ok(RootKey::Fetched),
+            Network::Mainnet => Ok(RootKey::Mainnet),
+            Network::Custom => {
+                let root_key = root_key.ok_or_else(|| {
+                    Error::io::<anyhow::Error>("a `custom` network requires a `root_key`", None)
+                })?;
+                hex::decode(root_key)
+                    .map(RootKey::Pinned)
+                    .map_err(|err| Error::io("the `root_key` is not hex encoded", Some(err)))
+            }
+        };
+
+        let canisters = config
+            .canisters
+            .iter()
+            .map(|canister| {
+                let id = canister.canister_id.as_str();
+                Ok((
+                    canister.canister_id.clone(),
+                    root_key(config.network(id), config.root_key(id))?,
+                ))
+            })
+            .collect::<HarnessResult<_>>()?;
+
+        Ok(Self {
+            identity,
+            principal,
+            root_key: root_key(config.network, config.root_key.as_deref())?,
+            canisters,
+            agents: Mutex::default(),
+        })
+    }
+
+    /// The principal the node calls the canisters as.
+    pub fn principal(&self) -> Principal {
+        self.principal
+    }
+
+    /// The agent reaching the canister through the replica, created on first use. An agent
+    /// that failed to be created is created again on the next call.
+    async fn agent(&self, canister_id: &str, icp_url: &str) -> HarnessResult<Agent> {
+        let root_key = self
+            .canisters
+            .get(canister_id)
+            .unwrap_or(&self.root_key)
+            .clone();
+        let cell = {
+            let mut agents = self.agents.lock().expect("lock is not poisoned; qed");
+            agents
+                .entry((icp_url.to_string(), root_key.clone()))
+                .or_default()
+                .clone()
+        };
+
+        cell.get_or_try_init(|| agent(icp_url, &root_key, self.identity.clone()))
+            .await
+            .cloned()
+    }
+
+    /// Queries the method of the canister, which takes no argument.
+    async fn query<R>(&self, canister_id: &str, icp_url: &str, method: &str) -> HarnessResult<R>
+    where
+        R: CandidType + DeserializeOwned,
+    {
+        let canister = canister_principal(canister_id)?;
+        let agent = self.agent(canister_id, icp_url).await?;
+
+        let response = agent
+            .query(&canister, method)
+            .with_arg(encode_args(method, ())?)
+            .call()
+            .await
+            .map_err(|err| agent_error(method, err))?;
+
+        decode_reply(method, &response)
+    }
+
+    /// Calls the update method of the canister and waits for its reply.
+    async fn update<A, R>(
+        &self,
+        canister_id: &str,
+        icp_url: &str,
+        method: &str,
+        args: A,
+    ) -> HarnessResult<R>
+    where
+        A: ArgumentEncoder,
+        R: CandidType + DeserializeOwned,
+    {
+        let canister = canister_principal(canister_id)?;
+        let agent = self.agent(canister_id, icp_url).await?;
+
+        let response = agent
+            .update(&canister, method)
+            .with_arg(encode_args(method, args)?)
+            .call_and_wait()
+            .await
+            .map_err(|err| agent_error(method, err))?;
+
+        decode_reply(method, &response)
+    }
+}
+
+impl IcpAgent for IcpAgentImpl {
+    async fn get_program_code(&self, canister_id: &str, icp_url: &str) -> HarnessResult<Vec<u8>> {
+        self.query(canister_id, icp_url, "get_program_code").await
+    }
+
+    async fn get_program_hash(&self, canister_id: &str, icp_url: &str) -> HarnessResult<String> {
+        self.query(canister_id, icp_url, "get_program_hash").await
+    }
+
+    async fn get_program_manifest(
+        &self,
+        canister_id: &str,
+        icp_url: &str,
+    ) -> HarnessResult<Option<SignedManifest>> {
+        self.query(canister_id, icp_url, "get_program_manifest")
+            .await
+    }
+
+    async fn get_schema(&self, canister_id: &str, icp_url: &str) -> HarnessResult<Schema> {
+        self.query(canister_id, icp_url, "get_schema").await
+    }
+
+    async fn get_program_id(&self, canister_id: &str, icp_url: &str) -> HarnessResult<ProgramId> {
+        self.query::<String>(canister_id, icp_url, "get_program_id")
+            .await?
+            .parse()
+    }
+
+    async fn register_device(
+        &self,
+        canister_id: &str,
+        icp_url: &str,
+        device_url: &str,
+    ) -> HarnessResult<String> {
+        self.update(canister_id, icp_url, "register_device", (device_url,))
+            .await
+    }
+
+    async fn remove_device(
+        &self,
+        canister_id: &str,
+        icp_url: &str,
+        device_url: &str,
+    ) -> HarnessResult<()> {
+        self.update(canister_id, icp_url, "remove_device", (device_url,))
+            .await
+    }
+}
+
+/// Creates an agent talking to the replica at the URL as the identity, verifying its replies with
+/// the root key.
+async fn agent(
+    icp_url: &str,
+    root_key: &RootKey,
+    identity: Arc<dyn Identity>,
+) -> HarnessResult<Agent> {
+    // `AgentBuilder::with_url` panics on a malformed URL
+    let url = icp_url.parse::<Url>().map_err(|err| {
+        Error::io(
+            &format!("`{icp_url}` is not a valid replica URL"),
+            Some(err),
+        )
+    })?;
+    let local = is_loopback(&url);
+    let agent = Agent::builder()
+        .with_route_provider(url)
+        .with_arc_identity(identity)
+        .with_verify_query_signatures(true)
+        .build()
+        .map_err(|err| agent_error("build", err))?;
+
+    match root_key {
+        RootKey::Fetched => {
+            if !local {
+                tracing::warn!(
+                    url = icp_url,
+                    "trusting the root key of a replica that is not on this host, use the `mainnet` or `custom` network instead"
+                );
+            }
+            agent
+                .fetch_root_key()
+                .await
+                .map_err(|err| agent_error("fetch_root_key", err))?;
+        }
+        // the agent verifies replies with the IC root key by default
+        RootKey::Mainnet => {}
+        RootKey::Pinned(root_key) => agent.set_root_key(root_key.clone()),
+    }
+
+    Ok(agent)
+}
+
+fn is_loopback(url: &Url) -> bool {
+    match url.host() {
+        Some(Host::Domain(domain)) => domain == "localhost",
+        Some(Host::Ipv4(ip)) => ip.is_loopback(),
+        Some(Host::Ipv6(ip)) => ip.is_loopback(),
+        None => false,
+    }
+}
+
+fn canister_principal(canister_id: &str) -> HarnessResult<Principal> {
+    Principal::from_text(canister_id).map_err(|err| {
+        Error::io(
+            &format!("`{canister_id}` is not a valid canister id"),
+            Some(err),
+        )
+    })
+}
+
+fn encode_args<A: ArgumentEncoder>(method: &str, args: A) -> HarnessResult<Vec<u8>> {
+    candid::encode_args(args).map_err(|err| Error::Internal {
+        message: format!("failed to encode the arguments of `{method}`"),
+        inner: Some(Box::new(err)),
+    })
+}
+
+fn decode_reply<R>(method: &str, response: &[u8]) -> HarnessResult<R>
+where
+    R: CandidType + DeserializeOwned,
+{
+    Decode!(response, R).map_err(|err| Error::Canister {
+        message: format!("the canister answered `{method}` with an unexpected reply"),
+        inner: Some(Box::new(err)),
+    })
+}
+
+/// Maps the failure of the agent to the harness error it is answered with.
+fn agent_error(method: &str, err: AgentError) -> Error {
+    let message = match &err {
+        AgentError::InvalidReplicaUrl(_) | AgentError::UrlParseError(_) => {
+            return Error::io("the replica URL is invalid", Some(err));
+        }
+        AgentError::PrincipalError(_) => return Error::io("the canister id is invalid", Some(err)),
+        AgentError::CertifiedReject(reject) | AgentError::UncertifiedReject(reject) => {
+            format!(
+                "the canister rejected `{method}`: {}",
+                reject.reject_message
+            )
+        }
+        AgentError::TransportError(_) | AgentError::HttpError(_) => {
+            format!("failed to reach the replica for `{method}`")
+        }
+        _ => format!("failed to call `{method}` on the canister"),
+    };
+
+    Error::Canister {
+        message,
+        inner: Some(Box::new(err)),
+    }
+}
@@ -0,0 +1,227 @@
+//! The authentication of the procedure calls canisters make to the node.
+//!
+//! A canister signs its calls with the secret it issued to the device when it registered, see
+//! [`harness_primitives::auth`]. Every replica of the canister sends the same signed call, so a
+//! signature seen again within the replica window is not rejected: it is answered with the
+//! response to the first call instead of running the procedure again. A signature is remembered
+//! for as long as its timestamp is accepted, seen again once its response is no longer kept, or
+//! when the response was larger than [`MAX_REPLAY_BODY`], the call is refused as a replay. While
+//! [`MAX_REPLAYS`] signatures are remembered, new calls are turned away as busy.
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{to_bytes, Bytes, HttpBody},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use tokio::sync::OnceCell;
+
+use harness_primitives::error::Error;
+
+/// The largest response body kept to answer the replicas sending the same call.
+pub const MAX_REPLAY_BODY: u64 = 1024 * 1024;
+
+/// The most signed calls remembered at once.
+pub const MAX_REPLAYS: usize = 10_000;
+
+/// The signed calls served while their signatures are accepted, along with the responses kept
+/// for the replicas sending them within the replica window.
+pub(crate) struct Replays {
+    window: Duration,
+    max_age: Duration,
+    calls: Mutex<Calls>,
+}
+
+/// The response to the first call, `None` when it was too large to keep.
+type ReplayResponse = Arc<OnceCell<Option<CachedResponse>>>;
+
+/// The signatures seen, with their responses while they are kept.
+///
+/// Every signature is remembered and its response kept for the same time, so the signatures
+/// expire in the order they were seen and are dropped from the front of the queues.
+#[derive(Default)]
+struct Calls {
+    responses: HashMap<String, Option<ReplayResponse>>,
+    /// When the timestamp of each call is no longer accepted, whatever the node's clock was off
+    /// by.
+    expiring: VecDeque<(Instant, String)>,
+    /// When the response to each call stops being kept.
+    kept: VecDeque<(Instant, String)>,
+}
+
+impl Calls {
+    /// Drops the responses and the signatures that expired.
+    fn expire(&mut self, now: Instant) {
+        while self.kept.front().is_some_and(|(until, _)| *until <= now) {
+            let (_, signature) = self.kept.pop_front().expect("the queue is not empty; qed");
+            if let Some(response) = self.responses.get_mut(&signature) {
+                *response = None;
+            }
+        }
+        while self
+            .expiring
+            .front()
+            .is_some_and(|(until, _)| *until <= now)
+        {
+            let (_, signature) = self
+                .expiring
+                .pop_front()
+                .expect("the queue is not empty; qed");
+            self.responses.remove(&signature);
+        }
+    }
+}
+
+/// A response kept to answer the replicas sending the same call.
+#[derive(Clone)]
+struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl CachedResponse {
+    /// Buffers the response to keep it, giving it back when it is too large.
+    async fn new(response: Response) -> Result<Self, Response> {
+        let size = response.body().size_hint().upper();
+        if size.is_none_or(|size| size > MAX_REPLAY_BODY) {
+            return Err(response);
+        }
+
+        let (parts, body) = response.into_parts();
+        let body = to_bytes(body, MAX_REPLAY_BODY as usize)
+            .await
+            .unwrap_or_default();
+        Ok(Self {
+            status: parts.status,
+            headers: parts.headers,
+            body,
+        })
+    }
+}
+
+impl IntoResponse for CachedResponse {
+    fn into_response(self) -> Response {
+        (self.status, self.headers, self.body).into_response()
+    }
+}
+
+impl Replays {
+    /// Keeps the responses for the replica window and the signatures for as long as calls signed
+    /// `max_age` away from the node's clock are accepted.
+    pub fn new(window: Duration, max_age: Duration) -> Self {
+        Self {
+            window,
+            max_age,
+            calls: Mutex::default(),
+        }
+    }
+
+    /// Serves the call with the given signature once, later calls with the same signature wait
+    /// for its response and are answered with a copy of it.
+    ///
+    /// Fails with [`Error::Unauthorized`] for a signature seen before whose response is not
+    /// kept, and with [`Error::Busy`] for a new signature while [`MAX_REPLAYS`] are remembered.
+    pub async fn serve<F>(&self, signature: &str, call: F) -> Result<Response, Error>
+    where
+        F: Future<Output = Response>,
+    {
+        let response = {
+            let mut calls = self.calls.lock().expect("lock is not poisoned; qed");
+            let now = Instant::now();
+            calls.expire(now);
+
+            match calls.responses.get(signature) {
+                Some(Some(response)) => response.clone(),
+                Some(None) => return Err(replayed()),
+                None if calls.responses.len() >= MAX_REPLAYS => {
+                    return Err(Error::Busy {
+                        message: "too many signed calls within the replica window".to_string(),
+                    })
+                }
+                None => {
+                    let response = Arc::new(OnceCell::new());
+                    calls
+                        .responses
+                        .insert(signature.to_string(), Some(response.clone()));
+                    // a call signed `max_age` ahead of the node's clock is accepted until
+                    // `max_age` after it
+                    calls.expiring.push_back((
+                        now + (2 * self.max_age).max(self.window),
+                        signature.to_string(),
+                    ));
+                    calls
+                        .kept
+                        .push_back((now + self.window, signature.to_string()));
+                    response
+                }
+            }
+        };
+
+        let mut first = None;
+        let cached = response
+            .get_or_init(|| async {
+                match CachedResponse::new(call.await).await {
+                    Ok(cached) => Some(cached),
+                    Err(response) => {
+                        first = Some(response);
+                        None
+                    }
+                }
+            })
+            .await;
+
+        if let Some(response) = first {
+            return Ok(response);
+        }
+        // the response to the first call was not kept, the call is not run again
+        cached
+            .as_ref()
+            .map(|cached| cached.clone().into_response())
+            .ok_or_else(replayed)
+    }
+}
+
+fn replayed() -> Error {
+    Error::Unauthorized {
+        message: "the call was already served".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calls_expire_in_order() {
+        let mut calls = Calls::default();
+        let now = Instant::now();
+        for (seen, signature) in [(0, "a"), (1, "b")] {
+            let seen = now + Duration::from_secs(seen);
+            calls
+                .responses
+                .insert(signature.to_string(), Some(Arc::default()));
+            calls
+                .kept
+                .push_back((seen + Duration::from_secs(1), signature.to_string()));
+            calls
+                .expiring
+                .push_back((seen + Duration::from_secs(10), signature.to_string()));
+        }
+
+        // the response is dropped first, the signature is still remembered
+        calls.expire(now + Duration::from_secs(1));
+        assert!(calls.responses["a"].is_none());
+        assert!(calls.responses["b"].is_some());
+
+        calls.expire(now + Duration::from_secs(10));
+        assert!(!calls.responses.contains_key("a"));
+        assert!(calls.responses["b"].is_none());
+        assert_eq!(calls.expiring.len(), 1);
+    }
+}
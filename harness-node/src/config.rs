@@ -0,0 +1,717 @@
+//! The configuration of the harness node. It is read from a TOML file, the `harness-node` binary
+//! then applies its command line flags on top of it.
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use harness_primitives::{
+    error::{Error, Result},
+    harness_os::ProgramConfig,
+    http::PullProgram,
+    program::{CallQuota, ResourcePolicy},
+};
+
+use crate::trust::TrustedPublisher;
+
+/// The node configuration, every field is optional in the file and falls back to its default.
+///
+/// ```toml
+/// bind_address = "0.0.0.0"
+/// port = 8080
+/// data_dir = "/var/lib/harness"
+/// log_level = "info"
+/// log_format = "json"
+/// max_request_size = 2097152
+/// shutdown_timeout_ms = 30000
+/// device_url = "https://device.example.com"
+/// deregister_on_shutdown = true
+/// register_on_startup = true
+/// registration_renewal_ms = 300000
+/// require_signed_calls = true
+/// signature_max_age_ms = 300000
+/// replica_window_ms = 60000
+/// network = "mainnet"
+/// identity_pem = "/etc/harness-node/identity.pem"
+///
+/// [admin]
+/// bind_address = "127.0.0.1"
+/// port = 8081
+/// token = "change-me"
+///
+/// [limits]
+/// pool_size = 4
+/// checkout_timeout_ms = 30000
+/// call_timeout_ms = 10000
+/// max_memory_bytes = 67108864
+/// max_table_elements = 10000
+/// fuel_per_call = 1000000000
+/// rate_per_sec = 50
+/// burst = 100
+/// max_concurrent_calls = 8
+///
+/// [canister_quota]
+/// rate_per_sec = 200
+/// max_concurrent_calls = 16
+///
+/// [[canisters]]
+/// canister_id = "bkyz2-fmaaa-aaaaa-qaaaq-cai"
+/// url = "http://127.0.0.1:4943"
+/// program_id = "hello"
+/// device_secret = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+/// network = "local"
+/// load_on_demand = true
+///
+/// [canisters.limits]
+/// pool_size = 1
+/// rate_per_sec = 10
+///
+/// [[trusted_publishers]]
+/// name = "acme"
+/// public_key = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a"
+/// ```
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The address the node listens on, IPv4 or IPv6.
+    pub bind_address: IpAddr,
+    /// The port the node listens on, `0` picks a random port.
+    pub port: u16,
+    /// The directory where the node keeps its state.
+    pub data_dir: PathBuf,
+    /// The verbosity of the node logs.
+    pub log_level: LogLevel,
+    /// How the node logs are written.
+    pub log_format: LogFormat,
+    /// The largest request body the node accepts, in bytes.
+    pub max_request_size: usize,
+    /// How long in-flight calls are given to finish once the node is asked to shut down, in
+    /// milliseconds.
+    pub shutdown_timeout_ms: u64,
+    /// The URL the canisters reach the node at, as it was registered with them.
+    pub device_url: Option<String>,
+    /// Whether the device is removed from the canisters of its programs when the node shuts
+    /// down, requires `device_url`.
+    pub deregister_on_shutdown: bool,
+    /// Whether the device registers its `device_url` with the configured canisters when the node
+    /// starts and deregisters when it shuts down, requires `device_url`.
+    pub register_on_startup: bool,
+    /// How often the registrations made on startup are renewed, in milliseconds. A canister
+    /// that lost the device, upgraded or reinstalled, learns of it again. `0` only registers on
+    /// startup.
+    pub registration_renewal_ms: u64,
+    /// Whether procedure calls must be signed by the canister of the program, with the secret
+    /// it issued to the device when it registered.
+    pub require_signed_calls: bool,
+    /// How old a signed call may be when it reaches the node, in milliseconds.
+    pub signature_max_age_ms: u64,
+    /// How long the response to a signed call is kept for the replicas of the canister sending
+    /// the same call, in milliseconds. Within this window they are answered with the response to
+    /// the first of them. The same call seen again later is refused as a replay.
+    pub replica_window_ms: u64,
+    /// The IC network the canisters are reached on, unless configured for the canister.
+    pub network: Network,
+    /// The hex encoded DER root key of the `custom` network.
+    pub root_key: Option<String>,
+    /// The PEM file of the identity the node calls the canisters with, an ed25519 or secp256k1
+    /// private key. Defaults to `identity.pem` in the data directory, generated on first start.
+    pub identity_pem: Option<PathBuf>,
+    /// The listener serving the program management routes.
+    pub admin: AdminConfig,
+    /// The limits applied to every loaded program.
+    pub limits: ProgramLimits,
+    /// The quota shared by the programs pulled from the same canister.
+    pub canister_quota: QuotaLimits,
+    /// The canisters whose programs are pulled when the node starts.
+    pub canisters: Vec<CanisterConfig>,
+    /// The publishers whose signed programs are loaded, any program is loaded when empty.
+    pub trusted_publishers: Vec<TrustedPublisher>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 0,
+            data_dir: default_data_dir(),
+            log_level: LogLevel::default(),
+            log_format: LogFormat::default(),
+            max_request_size: harness_primitives::http::MAX_BODY_SIZE,
+            shutdown_timeout_ms: 30_000,
+            device_url: None,
+            deregister_on_shutdown: false,
+            register_on_startup: false,
+            registration_renewal_ms: 300_000,
+            require_signed_calls: true,
+            signature_max_age_ms: 300_000,
+            replica_window_ms: 60_000,
+            network: Network::default(),
+            root_key: None,
+            identity_pem: None,
+            admin: AdminConfig::default(),
+            limits: ProgramLimits::default(),
+            canister_quota: QuotaLimits::default(),
+            canisters: Vec::new(),
+            trusted_publishers: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the configuration from a TOML file.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path).map_err(|err| {
+            Error::io(
+                &format!("failed to read the config file {}", path.display()),
+                err.into(),
+            )
+        })?;
+
+        contents.parse()
+    }
+
+    /// The address the node binds to.
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_address, self.port)
+    }
+
+    /// The settings for a program pulled from the canister, the limits configured for the
+    /// canister take precedence over the node wide limits.
+    pub fn program_config(&self, canister_id: &str) -> ProgramConfig {
+        match self.canister(canister_id) {
+            Some(canister) => canister.limits.or(&self.limits).program_config(),
+            None => self.limits.program_config(),
+        }
+    }
+
+    /// The quota of a program pulled from the canister, the limits configured for the canister
+    /// take precedence over the node wide limits.
+    pub fn quota(&self, canister_id: &str) -> CallQuota {
+        match self.canister(canister_id) {
+            Some(canister) => canister.limits.or(&self.limits).quota(),
+            None => self.limits.quota(),
+        }
+    }
+
+    /// How long in-flight calls are given to finish once the node is asked to shut down.
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_millis(self.shutdown_timeout_ms)
+    }
+
+    /// How often the registrations made on startup are renewed, `None` when they are not.
+    pub fn registration_renewal(&self) -> Option<Duration> {
+        (self.registration_renewal_ms != 0)
+            .then(|| Duration::from_millis(self.registration_renewal_ms))
+    }
+
+    /// How old a signed call may be when it reaches the node.
+    pub fn signature_max_age(&self) -> Duration {
+        Duration::from_millis(self.signature_max_age_ms)
+    }
+
+    /// How long the response to a signed call is kept for the replicas sending the same call.
+    pub fn replica_window(&self) -> Duration {
+        Duration::from_millis(self.replica_window_ms)
+    }
+
+    /// The IC network the canister is reached on.
+    pub fn network(&self, canister_id: &str) -> Network {
+        self.canister(canister_id)
+            .and_then(|canister| canister.network)
+            .unwrap_or(self.network)
+    }
+
+    /// The hex encoded root key replies from the canister are verified with on a `custom`
+    /// network.
+    pub fn root_key(&self, canister_id: &str) -> Option<&str> {
+        self.canister(canister_id)
+            .and_then(|canister| canister.root_key.as_deref())
+            .or(self.root_key.as_deref())
+    }
+
+    /// The PEM file of the node identity, and whether it is generated when missing.
+    pub fn identity_pem(&self) -> (PathBuf, bool) {
+        match &self.identity_pem {
+            Some(path) => (path.clone(), false),
+            None => (self.data_dir.join("identity.pem"), true),
+        }
+    }
+
+    /// Checks that the settings can be served together, once every source of settings is
+    /// applied.
+    pub fn validate(&self) -> Result<()> {
+        if self.deregister_on_shutdown && self.device_url.is_none() {
+            return Err(Error::io::<anyhow::Error>(
+                "`deregister_on_shutdown` requires the `device_url` the device was registered with",
+                None,
+            ));
+        }
+
+        if self.register_on_startup && self.device_url.is_none() {
+            return Err(Error::io::<anyhow::Error>(
+                "`register_on_startup` requires the `device_url` the canisters reach the device at",
+                None,
+            ));
+        }
+
+        // the node wide network applies to the canisters pulled through `POST /program`
+        let networks = self
+            .canisters
+            .iter()
+            .map(|canister| {
+                (
+                    canister.network.unwrap_or(self.network),
+                    canister.root_key.as_ref().or(self.root_key.as_ref()),
+                )
+            })
+            .chain([(self.network, self.root_key.as_ref())]);
+        for (network, root_key) in networks {
+            let valid = root_key.is_some_and(|root_key| hex::decode(root_key).is_ok());
+            if network == Network::Custom && !valid {
+                return Err(Error::io::<anyhow::Error>(
+                    "a `custom` network requires the hex encoded `root_key` its replies are verified with",
+                    None,
+                ));
+            }
+        }
+
+        // any local process can reach a loopback listener, on devices shared between teams too
+        if self.admin.token.as_deref().is_none_or(str::is_empty) {
+            return Err(Error::io::<anyhow::Error>(
+                "the admin listener requires a `token`, set in the `[admin]` table or by `--admin-token`",
+                None,
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the configured canister, if any.
+    pub fn canister(&self, canister_id: &str) -> Option<&CanisterConfig> {
+        self.canisters
+            .iter()
+            .find(|canister| canister.canister_id == canister_id)
+    }
+}
+
+impl std::str::FromStr for Config {
+    type Err = Error;
+
+    /// Parses the configuration, it is checked by [`Config::validate`] once the command line
+    /// flags are applied on top of it.
+    fn from_str(s: &str) -> Result<Self> {
+        toml::from_str(s).map_err(|err| Error::io("failed to parse the config file", err.into()))
+    }
+}
+
+/// The listener serving the program management routes, kept apart from the procedure calls.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// The address the admin listener binds to, only the loopback interface by default.
+    pub bind_address: IpAddr,
+    /// The port of the admin listener, `0` picks a random port.
+    pub port: u16,
+    /// The bearer token admin requests must carry in their `Authorization` header, the node
+    /// does not start without it.
+    pub token: Option<String>,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 0,
+            token: None,
+        }
+    }
+}
+
+impl AdminConfig {
+    /// The address the admin listener binds to.
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_address, self.port)
+    }
+}
+
+/// The verbosity of the node logs.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+            Self::Trace => "trace",
+        }
+    }
+}
+
+/// The IC network a canister is reached on, deciding the root key its replies are verified with.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    /// A replica started by `dfx`, the root key is fetched from the replica itself. Only safe
+    /// against a replica the device trusts.
+    Local,
+    /// The Internet Computer, replies are verified with the IC root key pinned in the agent.
+    #[default]
+    Mainnet,
+    /// Another network, replies are verified with the configured `root_key`.
+    Custom,
+}
+
+/// How the node logs are written.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors.
+    Json,
+}
+
+/// The resource limits of a program, unset values fall back to the node wide limits and then to
+/// the [`ProgramConfig`] defaults.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProgramLimits {
+    /// The number of calls into the program that can run in parallel.
+    pub pool_size: Option<usize>,
+    /// How long a call waits for an idle instance in milliseconds, `0` waits indefinitely.
+    pub checkout_timeout_ms: Option<u64>,
+    /// How long a call may run in milliseconds before the guest is interrupted, `0` lets calls
+    /// run until they finish.
+    pub call_timeout_ms: Option<u64>,
+    /// The largest the linear memory of the program may grow to in bytes, `0` does not limit it.
+    pub max_memory_bytes: Option<u64>,
+    /// The largest the tables of the program may grow to in elements, `0` does not limit them.
+    pub max_table_elements: Option<u32>,
+    /// The fuel a single call may consume, `0` does not limit it.
+    pub fuel_per_call: Option<u64>,
+    /// The calls admitted per second on average, `0` does not limit the rate.
+    pub rate_per_sec: Option<u32>,
+    /// The calls admitted at once after a quiet period, defaults to `rate_per_sec`.
+    pub burst: Option<u32>,
+    /// The calls into the program that may run at the same time, beyond `pool_size` they are
+    /// turned away instead of waiting for an instance. `0` does not limit them.
+    pub max_concurrent_calls: Option<u32>,
+}
+
+impl ProgramLimits {
+    /// Fills the unset limits from `other`.
+    pub fn or(&self, other: &Self) -> Self {
+        Self {
+            pool_size: self.pool_size.or(other.pool_size),
+            checkout_timeout_ms: self.checkout_timeout_ms.or(other.checkout_timeout_ms),
+            call_timeout_ms: self.call_timeout_ms.or(other.call_timeout_ms),
+            max_memory_bytes: self.max_memory_bytes.or(other.max_memory_bytes),
+            max_table_elements: self.max_table_elements.or(other.max_table_elements),
+            fuel_per_call: self.fuel_per_call.or(other.fuel_per_call),
+            rate_per_sec: self.rate_per_sec.or(other.rate_per_sec),
+            burst: self.burst.or(other.burst),
+            max_concurrent_calls: self.max_concurrent_calls.or(other.max_concurrent_calls),
+        }
+    }
+
+    /// Converts the limits to the quota enforced on the calls into the program.
+    pub fn quota(&self) -> CallQuota {
+        call_quota(self.rate_per_sec, self.burst, self.max_concurrent_calls)
+    }
+
+    /// Converts the limits to the settings used when loading the program.
+    pub fn program_config(&self) -> ProgramConfig {
+        let mut config = ProgramConfig::default();
+        if let Some(pool_size) = self.pool_size {
+            config.pool_size = pool_size;
+        }
+        if let Some(timeout) = self.checkout_timeout_ms {
+            config.checkout_timeout = (timeout != 0).then(|| Duration::from_millis(timeout));
+        }
+        if let Some(timeout) = self.call_timeout_ms {
+            config.call_timeout = (timeout != 0).then(|| Duration::from_millis(timeout));
+        }
+        config.policy = ResourcePolicy {
+            max_memory_bytes: self.max_memory_bytes.filter(|max| *max != 0),
+            max_table_elements: self.max_table_elements.filter(|max| *max != 0),
+            fuel_per_call: self.fuel_per_call.filter(|fuel| *fuel != 0),
+        };
+        config
+    }
+}
+
+/// The quota shared by the programs pulled from the same canister, so that a canister cannot
+/// take over the device by spreading its calls across programs.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaLimits {
+    /// The calls admitted per second on average, `0` does not limit the rate.
+    pub rate_per_sec: Option<u32>,
+    /// The calls admitted at once after a quiet period, defaults to `rate_per_sec`.
+    pub burst: Option<u32>,
+    /// The calls that may run at the same time, `0` does not limit them.
+    pub max_concurrent_calls: Option<u32>,
+}
+
+impl QuotaLimits {
+    /// Converts the limits to the quota enforced on the calls of each canister.
+    pub fn quota(&self) -> CallQuota {
+        call_quota(self.rate_per_sec, self.burst, self.max_concurrent_calls)
+    }
+}
+
+fn call_quota(
+    rate_per_sec: Option<u32>,
+    burst: Option<u32>,
+    max_concurrent_calls: Option<u32>,
+) -> CallQuota {
+    let rate_per_sec = rate_per_sec.filter(|rate| *rate != 0);
+    CallQuota {
+        burst: burst.filter(|_| rate_per_sec.is_some()),
+        rate_per_sec,
+        max_concurrent_calls: max_concurrent_calls.filter(|max| *max != 0),
+    }
+}
+
+/// A canister whose program is served by the node.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CanisterConfig {
+    pub canister_id: String,
+    /// The URL of the IC replica the canister is reached through.
+    pub url: String,
+    /// The id the canister calls the program by, queried from the canister when unset.
+    #[serde(default)]
+    pub program_id: Option<String>,
+    /// The hex encoded secret the canister issued when the device registered with it, not
+    /// needed with `register_on_startup`.
+    #[serde(default)]
+    pub device_secret: Option<String>,
+    /// Overrides the node wide limits for this program.
+    #[serde(default)]
+    pub limits: ProgramLimits,
+    /// Whether the program is pulled on the first call the canister routes to the device instead
+    /// of when the node starts.
+    #[serde(default)]
+    pub load_on_demand: bool,
+    /// Overrides the node wide network for this canister.
+    #[serde(default)]
+    pub network: Option<Network>,
+    /// The hex encoded DER root key of the canister's `custom` network, defaults to the node
+    /// wide `root_key`.
+    #[serde(default)]
+    pub root_key: Option<String>,
+}
+
+impl From<&CanisterConfig> for PullProgram {
+    fn from(canister: &CanisterConfig) -> Self {
+        Self {
+            canister_id: canister.canister_id.clone(),
+            url: canister.url.clone(),
+            program_id: canister.program_id.clone(),
+            // the limits of the canister are applied by the node when the program is loaded
+            policy: None,
+            quota: None,
+            device_secret: canister.device_secret.clone(),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn default_data_dir() -> PathBuf {
+    match std::env::var_os("HOME") {
+        Some(home) => PathBuf::from(home).join(".local/share/harness-node"),
+        None => PathBuf::from("harness-node"),
+    }
+}
+
+#[cfg(windows)]
+fn default_data_dir() -> PathBuf {
+    match std::env::var_os("LOCALAPPDATA") {
+        Some(app_data) => PathBuf::from(app_data).join("harness-node"),
+        None => PathBuf::from("harness-node"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CANISTER_ID: &str = "bkyz2-fmaaa-aaaaa-qaaaq-cai";
+
+    /// Parses and validates the config, with the admin token the node requires to start.
+    fn validate(toml: &str) -> Result<()> {
+        let mut config = toml.parse::<Config>().unwrap();
+        config
+            .admin
+            .token
+            .get_or_insert_with(|| "secret".to_string());
+        config.validate()
+    }
+
+    #[test]
+    fn test_config_from_toml() {
+        let config: Config = r#"
+            bind_address = "::"
+            port = 8080
+            log_level = "debug"
+        "#
+        .parse()
+        .unwrap();
+
+        assert_eq!(config.socket_addr().to_string(), "[::]:8080");
+        assert_eq!(config.log_level, LogLevel::Debug);
+        assert_eq!(
+            config.max_request_size,
+            harness_primitives::http::MAX_BODY_SIZE
+        );
+
+        assert!("prot = 8080".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn test_limits_config() {
+        let config: Config = r#"
+            [limits]
+            pool_size = 4
+            checkout_timeout_ms = 500
+            fuel_per_call = 1000000
+            max_memory_bytes = 1048576
+
+            [[canisters]]
+            canister_id = "bkyz2-fmaaa-aaaaa-qaaaq-cai"
+            url = "http://127.0.0.1:4943"
+
+            [canisters.limits]
+            checkout_timeout_ms = 0
+            call_timeout_ms = 250
+            fuel_per_call = 0
+        "#
+        .parse()
+        .unwrap();
+
+        // a canister overrides the node limits, `0` lifting a limit
+        let program = config.program_config(CANISTER_ID);
+        assert_eq!(program.pool_size, 4);
+        assert_eq!(program.checkout_timeout, None);
+        assert_eq!(program.call_timeout, Some(Duration::from_millis(250)));
+        assert_eq!(
+            program.policy,
+            ResourcePolicy {
+                max_memory_bytes: Some(1048576),
+                ..Default::default()
+            }
+        );
+
+        let other = config.program_config("aaaaa-aa");
+        assert_eq!(other.checkout_timeout, Some(Duration::from_millis(500)));
+        assert_eq!(other.policy.fuel_per_call, Some(1000000));
+    }
+
+    #[test]
+    fn test_quota_config() {
+        let config: Config = r#"
+            [limits]
+            rate_per_sec = 100
+            max_concurrent_calls = 8
+
+            [canister_quota]
+            max_concurrent_calls = 16
+
+            [[canisters]]
+            canister_id = "bkyz2-fmaaa-aaaaa-qaaaq-cai"
+            url = "http://127.0.0.1:4943"
+
+            [canisters.limits]
+            rate_per_sec = 10
+            burst = 20
+            max_concurrent_calls = 0
+        "#
+        .parse()
+        .unwrap();
+
+        assert_eq!(
+            config.quota(CANISTER_ID),
+            CallQuota {
+                rate_per_sec: Some(10),
+                burst: Some(20),
+                max_concurrent_calls: None,
+            }
+        );
+        assert_eq!(config.quota("aaaaa-aa").max_concurrent_calls, Some(8));
+        assert_eq!(config.canister_quota.quota().max_concurrent_calls, Some(16));
+    }
+
+    #[test]
+    fn test_admin_config() {
+        // the admin listener is always served with a token, even on the loopback interface
+        assert!(Config::default().validate().is_err());
+        assert!(validate("[admin]\ntoken = \"\"").is_err());
+        assert!(validate("[admin]\nbind_address = \"0.0.0.0\"").is_ok());
+    }
+
+    #[test]
+    fn test_registration_config() {
+        assert!(validate("deregister_on_shutdown = true").is_err());
+        assert!(validate("register_on_startup = true").is_err());
+
+        // the flags applied on top of the file are validated along with it
+        let mut config: Config = "register_on_startup = true".parse().unwrap();
+        config.device_url = Some("https://device.example.com".to_string());
+        config.admin.token = Some("secret".to_string());
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_network_config() {
+        let config: Config = r#"
+            [[canisters]]
+            canister_id = "bkyz2-fmaaa-aaaaa-qaaaq-cai"
+            url = "http://127.0.0.1:4943"
+            program_id = "hello"
+            network = "local"
+        "#
+        .parse()
+        .unwrap();
+
+        assert_eq!(config.network(CANISTER_ID), Network::Local);
+        assert_eq!(config.network("aaaaa-aa"), Network::Mainnet);
+
+        assert!(validate("network = \"custom\"").is_err());
+        assert!(validate("network = \"custom\"\nroot_key = \"not hex\"").is_err());
+        assert!(validate("network = \"custom\"\nroot_key = \"308182\"").is_ok());
+    }
+
+    #[test]
+    fn test_trusted_publishers_config() {
+        let config: Config = r#"
+            [[trusted_publishers]]
+            name = "acme"
+            public_key = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a"
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(config.trusted_publishers[0].name, "acme");
+
+        assert!(
+            r#"trusted_publishers = [{ name = "acme", public_key = "d75a98" }]"#
+                .parse::<Config>()
+                .is_err()
+        );
+    }
+}
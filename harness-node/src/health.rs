@@ -0,0 +1,63 @@
+//! The readiness of the node to take on work, served by `GET /readyz`.
+use std::path::Path;
+
+use serde::Serialize;
+
+/// Reports whether the node can accept work and why.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Readiness {
+    /// Whether the node can accept work.
+    pub ready: bool,
+    /// Whether the node is shutting down, waiting for in-flight calls to finish.
+    pub draining: bool,
+    /// The configured programs still being pulled after the node started.
+    pub pending_pulls: usize,
+    /// The version of the node.
+    pub version: &'static str,
+    /// The version of the API the node speaks, see [`harness_primitives::http::PROTOCOL_VERSION`].
+    pub protocol_version: u32,
+    /// How long the node has been running, in seconds.
+    pub uptime_secs: u64,
+    /// The number of loaded programs.
+    pub programs: usize,
+    /// The instances of all the loaded programs.
+    pub pool: PoolUsage,
+    /// Whether pulled programs can be stored in the data directory.
+    pub data_dir_writable: bool,
+}
+
+/// The instances of all the loaded programs and how many of them are serving calls.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct PoolUsage {
+    pub size: usize,
+    pub in_use: usize,
+    /// The share of the instances serving calls, from `0.0` to `1.0`.
+    pub saturation: f64,
+}
+
+impl PoolUsage {
+    pub fn new(size: usize, in_use: usize) -> Self {
+        Self {
+            size,
+            in_use,
+            saturation: if size == 0 {
+                0.0
+            } else {
+                in_use as f64 / size as f64
+            },
+        }
+    }
+
+    /// Whether every instance is serving a call, new calls would have to wait for one.
+    pub fn saturated(&self) -> bool {
+        self.size > 0 && self.in_use >= self.size
+    }
+}
+
+/// Checks that a file can be written to the directory, creating it if needed.
+pub async fn is_writable(dir: &Path) -> bool {
+    let probe = dir.join(".probe");
+    tokio::fs::create_dir_all(dir).await.is_ok()
+        && tokio::fs::write(&probe, b"").await.is_ok()
+        && tokio::fs::remove_file(&probe).await.is_ok()
+}
@@ -0,0 +1,87 @@
+//! The identity the node calls the canisters with, so that a canister can tell the device apart
+//! by its principal.
+//!
+//! The identity is read from a PEM encoded private key, ed25519 or secp256k1 as created by
+//! `dfx identity`. When the node is not given one, an ed25519 key is generated on first start and
+//! kept in the data directory, the principal of the node stays the same across restarts.
+use std::{
+    io::{ErrorKind, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use ic_agent::{
+    identity::{BasicIdentity, Secp256k1Identity},
+    Identity,
+};
+use ring::{rand::SystemRandom, signature::Ed25519KeyPair};
+
+use harness_primitives::error::{Error, Result};
+
+/// Reads the identity from the PEM file, generating it first when missing and `generate` is set.
+pub fn load(path: &Path, generate: bool) -> Result<Arc<dyn Identity>> {
+    let pem = match std::fs::read(path) {
+        Ok(pem) => pem,
+        Err(err) if err.kind() == ErrorKind::NotFound && generate => {
+            let pem = generate_pem()?;
+            write_pem(path, &pem)?;
+            tracing::info!(path = %path.display(), "generated the node identity");
+            pem
+        }
+        Err(err) => {
+            return Err(Error::io(
+                &format!("failed to read the identity {}", path.display()),
+                Some(err),
+            ))
+        }
+    };
+
+    if let Ok(identity) = BasicIdentity::from_pem(pem.as_slice()) {
+        return Ok(Arc::new(identity));
+    }
+    Secp256k1Identity::from_pem(pem.as_slice())
+        .map(|identity| Arc::new(identity) as Arc<dyn Identity>)
+        .map_err(|err| {
+            Error::io(
+                &format!(
+                    "{} is not an ed25519 or secp256k1 private key",
+                    path.display()
+                ),
+                Some(err),
+            )
+        })
+}
+
+fn generate_pem() -> Result<Vec<u8>> {
+    let document =
+        Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).map_err(|_| Error::Internal {
+            message: "failed to generate the node identity".to_string(),
+            inner: None,
+        })?;
+
+    Ok(pem::encode(&pem::Pem::new("PRIVATE KEY", document.as_ref())).into_bytes())
+}
+
+/// Writes the private key, readable by the node's user only.
+fn write_pem(path: &Path, pem: &[u8]) -> Result<()> {
+    let err = |err| {
+        Error::io(
+            &format!("failed to write the identity {}", path.display()),
+            Some(err),
+        )
+    };
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(err)?;
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(path).map_err(err)?;
+    file.write_all(pem)
+        .and_then(|_| file.sync_all())
+        .map_err(err)
+}
@@ -0,0 +1,282 @@
+//! The programs loaded to the node and the description of them reported to operators.
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use harness_primitives::{
+    error::Result,
+    harness_os::ProgramConfig,
+    harness_os::ProgramPool,
+    program::{CallQuota, ProgramId, ResourcePolicy},
+    HarnessOs,
+};
+
+use crate::{
+    health::PoolUsage,
+    quota::Limiter,
+    storage::{unix_time, ProgramMetadata},
+};
+
+/// The loaded programs along with where they were pulled from.
+#[derive(Default)]
+pub(crate) struct Programs {
+    harness_os: HarnessOs,
+    loaded: HashMap<ProgramId, LoadedProgram>,
+}
+
+struct LoadedProgram {
+    metadata: ProgramMetadata,
+    size: usize,
+    loaded_at: u64,
+    limiter: Arc<Limiter>,
+    /// The secret replaced when the device registered again, and when it was replaced.
+    previous_secret: Option<(String, Instant)>,
+}
+
+impl Programs {
+    /// Loads the program, replacing any program loaded with the same id.
+    pub async fn load(
+        &mut self,
+        program_id: ProgramId,
+        metadata: ProgramMetadata,
+        code: &[u8],
+        config: &ProgramConfig,
+        limiter: Limiter,
+    ) -> Result<()> {
+        let pool = ProgramPool::new(code, config).await?;
+        _ = self.swap(program_id, metadata, code.len(), Arc::new(pool), limiter);
+        Ok(())
+    }
+
+    /// Swaps in a program whose pool was created beforehand, returning the hex encoded SHA-256
+    /// hash of the version it replaced.
+    ///
+    /// The limiter of the replaced version is kept when the quota is unchanged, so that an
+    /// upgrade does not refill the bucket of its calls.
+    pub fn swap(
+        &mut self,
+        program_id: ProgramId,
+        metadata: ProgramMetadata,
+        size: usize,
+        pool: Arc<ProgramPool>,
+        limiter: Limiter,
+    ) -> Option<String> {
+        let limiter = match self.loaded.get(&program_id) {
+            Some(loaded)
+                if loaded.metadata.canister_id == metadata.canister_id
+                    && loaded.limiter.quota() == limiter.quota() =>
+            {
+                loaded.limiter.clone()
+            }
+            _ => Arc::new(limiter),
+        };
+        let program = LoadedProgram {
+            metadata,
+            size,
+            loaded_at: unix_time(),
+            limiter,
+            previous_secret: None,
+        };
+        _ = self.harness_os.swap_program(program_id.clone(), pool);
+        self.loaded
+            .insert(program_id, program)
+            .map(|replaced| replaced.metadata.sha256)
+    }
+
+    /// Unloads the program, noop if it is not loaded.
+    pub fn unload(&mut self, program_id: &ProgramId) {
+        self.harness_os.remove_program(program_id);
+        _ = self.loaded.remove(program_id);
+    }
+
+    /// Returns the instance pool of a loaded program.
+    pub fn pool(&self, program_id: &ProgramId) -> Option<Arc<ProgramPool>> {
+        self.harness_os.program(program_id)
+    }
+
+    /// Returns the limiter of the quota of a loaded program.
+    pub fn limiter(&self, program_id: &ProgramId) -> Option<Arc<Limiter>> {
+        Some(self.loaded.get(program_id)?.limiter.clone())
+    }
+
+    /// Whether the schema of the loaded program declares the procedure.
+    pub fn declares(&self, program_id: &ProgramId, procedure: &str) -> bool {
+        self.loaded
+            .get(program_id)
+            .and_then(|program| program.metadata.schema.as_ref())
+            .is_some_and(|schema| {
+                schema
+                    .services
+                    .iter()
+                    .any(|service| service.name == procedure)
+            })
+    }
+
+    /// Whether a program pulled from the canister is loaded.
+    pub fn serves_canister(&self, canister_id: &str) -> bool {
+        self.loaded
+            .values()
+            .any(|program| program.metadata.canister_id == canister_id)
+    }
+
+    /// The hex encoded secrets the calls into the loaded program may be signed with. The secret
+    /// replaced by [`Programs::rotate_secret`] is still accepted until `grace` has passed.
+    pub fn device_secrets(&self, program_id: &ProgramId, grace: Duration) -> Vec<String> {
+        let Some(program) = self.loaded.get(program_id) else {
+            return Vec::new();
+        };
+
+        let previous = program
+            .previous_secret
+            .as_ref()
+            .filter(|(_, replaced_at)| replaced_at.elapsed() <= grace)
+            .map(|(secret, _)| secret.clone());
+        program
+            .metadata
+            .device_secret
+            .clone()
+            .into_iter()
+            .chain(previous)
+            .collect()
+    }
+
+    /// Replaces the secret the calls of the canister are signed with in the programs pulled from
+    /// it, returning the metadata of the programs whose secret changed.
+    pub fn rotate_secret(&mut self, canister_id: &str, secret: &str) -> Vec<ProgramMetadata> {
+        self.loaded
+            .values_mut()
+            .filter(|program| {
+                program.metadata.canister_id == canister_id
+                    && program.metadata.device_secret.as_deref() != Some(secret)
+            })
+            .map(|program| {
+                let replaced = program.metadata.device_secret.replace(secret.to_string());
+                program.previous_secret = replaced.map(|replaced| (replaced, Instant::now()));
+                program.metadata.clone()
+            })
+            .collect()
+    }
+
+    /// Describes the loaded program.
+    pub fn info(&self, program_id: &ProgramId) -> Option<ProgramInfo> {
+        let program = self.loaded.get(program_id)?;
+        let pool = self.harness_os.program(program_id)?;
+        let stats = pool.stats();
+
+        Some(ProgramInfo {
+            program_id: program.metadata.program_id.clone(),
+            canister_id: program.metadata.canister_id.clone(),
+            url: program.metadata.url.clone(),
+            size: program.size,
+            sha256: program.metadata.sha256.clone(),
+            pulled_at: program.metadata.pulled_at,
+            loaded_at: program.loaded_at,
+            pool_size: pool.size(),
+            in_use: pool.in_use(),
+            invocations: stats.invocations,
+            failures: stats.failures,
+            last_error: stats.last_error,
+            policy: pool.policy().clone(),
+            quota: program.limiter.quota().clone(),
+            throttled: program.limiter.throttled(),
+            publisher: program.metadata.publisher.clone(),
+            version: program
+                .metadata
+                .manifest
+                .as_ref()
+                .and_then(|manifest| manifest.manifest().ok())
+                .map(|manifest| manifest.version)
+                .or_else(|| {
+                    let schema = program.metadata.schema.as_ref()?;
+                    Some(schema.version.clone())
+                }),
+            procedures: program
+                .metadata
+                .schema
+                .iter()
+                .flat_map(|schema| &schema.services)
+                .map(|service| service.name.clone())
+                .collect(),
+        })
+    }
+
+    /// The number of loaded programs.
+    pub fn len(&self) -> usize {
+        self.loaded.len()
+    }
+
+    /// The instances of all the loaded programs.
+    pub fn pool_usage(&self) -> PoolUsage {
+        let (size, in_use) = self
+            .harness_os
+            .program_ids()
+            .iter()
+            .filter_map(|id| self.harness_os.program(id))
+            .fold((0, 0), |(size, in_use), pool| {
+                (size + pool.size(), in_use + pool.in_use())
+            });
+        PoolUsage::new(size, in_use)
+    }
+
+    /// Describes every loaded program, ordered by program id.
+    pub fn infos(&self) -> Vec<ProgramInfo> {
+        let mut program_ids = self.harness_os.program_ids();
+        program_ids.sort();
+        program_ids.iter().filter_map(|id| self.info(id)).collect()
+    }
+}
+
+/// Describes a program loaded to the node, served by `GET /program`.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProgramInfo {
+    pub program_id: String,
+    /// The canister the program was pulled from.
+    pub canister_id: String,
+    /// The URL of the IC replica the program was pulled through.
+    pub url: String,
+    /// The size of the module in bytes.
+    pub size: usize,
+    /// The hex encoded SHA-256 hash of the module.
+    pub sha256: String,
+    /// When the program was pulled, in seconds since the unix epoch.
+    pub pulled_at: u64,
+    /// When the program was loaded by the running node, in seconds since the unix epoch.
+    pub loaded_at: u64,
+    /// The number of calls into the program that can run in parallel.
+    pub pool_size: usize,
+    /// The number of calls currently running.
+    pub in_use: usize,
+    /// The number of calls made into the program since it was loaded.
+    pub invocations: u64,
+    /// The number of those calls that failed.
+    pub failures: u64,
+    /// The error of the last failed call.
+    pub last_error: Option<String>,
+    /// The resources the program may use.
+    pub policy: ResourcePolicy,
+    /// How often and how many calls at once the program is served.
+    pub quota: CallQuota,
+    /// The number of calls turned away by the quota, of the program or of its canister.
+    pub throttled: u64,
+    /// The trusted publisher that signed the program.
+    pub publisher: Option<String>,
+    /// The version of the program, from its signed manifest or the schema of its canister.
+    pub version: Option<String>,
+    /// The procedures the program serves, from the schema of its canister.
+    pub procedures: Vec<String>,
+}
+
+/// The outcome of pulling a program, served by `POST /program`.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PulledProgram {
+    pub program_id: String,
+    /// The hex encoded SHA-256 hash of the module that was replaced, `None` when the program was
+    /// not loaded.
+    pub previous_sha256: Option<String>,
+    /// The hex encoded SHA-256 hash of the module now loaded.
+    pub sha256: String,
+}
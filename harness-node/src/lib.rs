@@ -1,191 +1,745 @@
 use std::{
-    future::Future,
-    io::Cursor,
-    net::{Ipv4Addr, SocketAddrV4},
+    collections::{BTreeSet, HashMap},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use candid::Decode;
-use ic_agent::{export::Principal, Agent, AgentError};
-use tokio::net::TcpListener;
+use tokio::{net::TcpListener, sync::RwLock, task::JoinHandle};
 
 use harness_primitives::{
+    auth::{decode_secret, SignedRequest},
     error::{Error, Result as HarnessResult},
-    http::{get_header, Header, Method, PullProgram, Request, Response},
-    HarnessOs,
+    harness_os::{Invocation, ProgramConfig, ProgramPool},
+    http::{PullProgram, PROTOCOL_VERSION},
+    internals::Schema,
+    program::ProgramId,
 };
 
+mod agent;
+mod auth;
+pub mod config;
+pub mod health;
+mod identity;
+pub mod inventory;
+pub mod metrics;
+pub mod quota;
+mod routes;
+pub mod storage;
+pub mod telemetry;
+pub mod trust;
+
+pub use agent::{IcpAgent, IcpAgentImpl};
+use auth::Replays;
+use config::{CanisterConfig, Config};
+use health::Readiness;
+use inventory::{ProgramInfo, Programs, PulledProgram};
+use metrics::Metrics;
+use quota::{CanisterQuotas, Limiter, QuotaScope};
+use storage::{ProgramMetadata, ProgramStore};
+use trust::TrustStore;
+
+/// The node server is shared between connections, procedure calls only need read access to the
+/// loaded programs while loading and unloading programs take exclusive access.
+///
+/// The HTTP routes are served through [`NodeServer::public_router`] and
+/// [`NodeServer::admin_router`], or through [`NodeServer::router`] on a single listener.
 pub struct NodeServer<T: IcpAgent> {
-    harness_os: HarnessOs,
+    programs: RwLock<Programs>,
     icp_agent: T,
+    store: ProgramStore,
+    trust: TrustStore,
+    replays: Replays,
+    canister_quotas: CanisterQuotas,
+    /// The secrets issued by the canisters the device registered with, by canister id.
+    registrations: Mutex<HashMap<String, String>>,
+    /// Held while a program is pulled on demand, so that it is pulled once.
+    loading: tokio::sync::Mutex<()>,
+    /// Held while the stored copy and the loaded version of a program change, by program id.
+    program_locks: Mutex<HashMap<ProgramId, Arc<tokio::sync::Mutex<()>>>>,
+    config: Config,
+    metrics: Metrics,
+    started_at: Instant,
+    draining: AtomicBool,
+    /// The configured programs still to be pulled on startup.
+    pending_pulls: AtomicUsize,
 }
 
+/// Creates a node server with the default [`Config`].
 pub fn new_node_server<T>(agent: T) -> NodeServer<T>
 where
-    T: IcpAgent + Send,
+    T: IcpAgent + Send + Sync,
+{
+    new_node_server_with_config(agent, Config::default())
+}
+
+/// Creates a node server, the config sets the request size limit, the limits of the programs
+/// loaded to the node and the data directory they are stored in.
+pub fn new_node_server_with_config<T>(agent: T, config: Config) -> NodeServer<T>
+where
+    T: IcpAgent + Send + Sync,
 {
     NodeServer {
-        harness_os: HarnessOs::default(),
+        programs: RwLock::new(Programs::default()),
         icp_agent: agent,
+        store: ProgramStore::new(&config.data_dir),
+        trust: TrustStore::new(&config.trusted_publishers),
+        replays: Replays::new(config.replica_window(), config.signature_max_age()),
+        canister_quotas: CanisterQuotas::new(config.canister_quota.quota()),
+        registrations: Mutex::default(),
+        loading: tokio::sync::Mutex::default(),
+        program_locks: Mutex::default(),
+        config,
+        metrics: Metrics::new(),
+        started_at: Instant::now(),
+        draining: AtomicBool::new(false),
+        pending_pulls: AtomicUsize::new(0),
     }
 }
 
-/// This is the interface for the ICP agent that is used to poll the IC canister.
-pub trait IcpAgent {
-    fn get_program_code(
+impl<T: IcpAgent> NodeServer<T> {
+    /// The configuration the node is running with.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Polls the program code from the canister and loads it to the device, with the limits
+    /// configured for the canister tightened by the policy of the payload. The program is stored
+    /// in the data directory so that it is restored when the node restarts.
+    ///
+    /// The module is only loaded when its hash matches the one published by the canister and,
+    /// when publishers are trusted, when it comes with a manifest signed by one of them.
+    ///
+    /// A program already loaded with the same id is upgraded: the new version is compiled and
+    /// initialized while the loaded one keeps serving calls, then swapped in at once. Calls
+    /// already running finish on the version they started on, and a version that fails to load
+    /// leaves the loaded one in place.
+    ///
+    /// Without a `device_secret`, the program is pulled with the secret issued when the device
+    /// registered with the canister on startup.
+    ///
+    /// The program is loaded under the id the canister declares, the one its calls carry. An
+    /// explicit `program_id` that differs from it is refused.
+    pub async fn pull_program(&self, mut program: PullProgram) -> HarnessResult<PulledProgram> {
+        let (program_id, schema) = self.discover_program(&program).await?;
+        if program.device_secret.is_none() {
+            program.device_secret = self.registered_secret(&program.canister_id);
+        }
+        match &program.device_secret {
+            Some(secret) => _ = decode_secret(secret)?,
+            None if self.config.require_signed_calls => {
+                return Err(Error::io::<anyhow::Error>(
+                    "the `device_secret` the canister issued when the device registered is required to verify its calls",
+                    None,
+                ))
+            }
+            None => {}
+        }
+        let code = self
+            .icp_agent
+            .get_program_code(&program.canister_id, &program.url)
+            .await?;
+
+        let published = self
+            .icp_agent
+            .get_program_hash(&program.canister_id, &program.url)
+            .await?;
+
+        let mut metadata = ProgramMetadata::new(&program, &program_id, schema, &code);
+        if !metadata.sha256.eq_ignore_ascii_case(published.trim()) {
+            return Err(Error::Integrity {
+                message: format!(
+                    "the program hash {} does not match the hash {published} published by the canister",
+                    metadata.sha256
+                ),
+            });
+        }
+
+        if !self.trust.is_open() {
+            metadata.manifest = self
+                .icp_agent
+                .get_program_manifest(&program.canister_id, &program.url)
+                .await?;
+            self.verify_manifest(&mut metadata)?;
+        }
+        let program_config = self.program_config(&metadata);
+        let limiter = self.limiter(&metadata);
+        let started = Instant::now();
+        let pool = ProgramPool::new(&code, &program_config).await?;
+        self.metrics
+            .observe_load(program_id.as_str(), started.elapsed());
+
+        // a program that would not survive a restart is not served, the pool is dropped unpublished
+        let _program = self.lock_program(&program_id).await;
+        self.store.save(&metadata, &code).await?;
+
+        let previous_sha256 = self.programs.write().await.swap(
+            program_id.clone(),
+            metadata.clone(),
+            code.len(),
+            Arc::new(pool),
+            limiter,
+        );
+
+        Ok(PulledProgram {
+            program_id: metadata.program_id,
+            previous_sha256,
+            sha256: metadata.sha256,
+        })
+    }
+
+    /// Queries the id and the schema the canister declares for its program, checking the id
+    /// the program is pulled with against them.
+    async fn discover_program(&self, program: &PullProgram) -> HarnessResult<(ProgramId, Schema)> {
+        let program_id = self
+            .icp_agent
+            .get_program_id(&program.canister_id, &program.url)
+            .await?;
+        let schema = self
+            .icp_agent
+            .get_schema(&program.canister_id, &program.url)
+            .await?;
+
+        if schema.program != program_id.as_str() {
+            return Err(Error::Canister {
+                message: format!(
+                    "the canister declares the program '{}' but its schema describes '{}'",
+                    program_id.as_str(),
+                    schema.program
+                ),
+                inner: None,
+            });
+        }
+        if let Some(expected) = &program.program_id {
+            if expected != program_id.as_str() {
+                return Err(Error::io::<anyhow::Error>(
+                    &format!(
+                        "the canister `{}` serves the program '{}', not '{expected}', leave `program_id` out to use the id the canister declares",
+                        program.canister_id,
+                        program_id.as_str()
+                    ),
+                    None,
+                ));
+            }
+        }
+
+        Ok((program_id, schema))
+    }
+
+    /// Loads the programs stored in the data directory, returning the restored program ids.
+    ///
+    /// A stored program that can no longer be loaded is skipped, it stays on disk until it is
+    /// pulled again or removed. So is a program whose publisher is no longer trusted.
+    pub async fn restore_programs(&self) -> HarnessResult<Vec<ProgramId>> {
+        let (programs, errors) = self.store.load_all().await?;
+        for err in errors {
+            tracing::warn!(error = %err, "failed to read a stored program");
+        }
+
+        let mut loaded = self.programs.write().await;
+        let mut restored = Vec::with_capacity(programs.len());
+        for program in programs {
+            let mut metadata = program.metadata;
+            if let Err(err) = self.verify_manifest(&mut metadata) {
+                tracing::warn!(program_id = metadata.program_id, error = %err, "failed to restore program");
+                continue;
+            }
+            let program_id = match metadata.program_id.parse::<ProgramId>() {
+                Ok(program_id) => program_id,
+                Err(err) => {
+                    tracing::warn!(program_id = metadata.program_id, error = %err, "failed to restore program");
+                    continue;
+                }
+            };
+            let program_config = self.program_config(&metadata);
+            let limiter = self.limiter(&metadata);
+            let name = metadata.program_id.clone();
+            let started = Instant::now();
+            match loaded
+                .load(
+                    program_id.clone(),
+                    metadata,
+                    &program.code,
+                    &program_config,
+                    limiter,
+                )
+                .await
+            {
+                Ok(()) => {
+                    self.metrics.observe_load(&name, started.elapsed());
+                    restored.push(program_id);
+                }
+                Err(err) => {
+                    tracing::warn!(program_id = name, error = %err, "failed to restore program")
+                }
+            }
+        }
+
+        Ok(restored)
+    }
+
+    /// Verifies the manifest of the program against the trusted publishers, recording the
+    /// publisher that signed it. Noop when no publisher is trusted.
+    fn verify_manifest(&self, metadata: &mut ProgramMetadata) -> HarnessResult<()> {
+        if self.trust.is_open() {
+            return Ok(());
+        }
+
+        let verified = self.trust.verify(
+            &metadata.program_id,
+            &metadata.sha256,
+            metadata.manifest.as_ref(),
+        )?;
+        metadata.publisher = Some(verified.publisher);
+        Ok(())
+    }
+
+    /// The settings of a stored program, the limits configured for its canister tightened by
+    /// the policy it was pulled with.
+    fn program_config(&self, metadata: &ProgramMetadata) -> ProgramConfig {
+        let mut config = self.config.program_config(&metadata.canister_id);
+        if let Some(policy) = &metadata.policy {
+            config.policy = config.policy.tightest(policy);
+        }
+        config
+    }
+
+    /// The limiter of a stored program, enforcing the quota configured for its canister
+    /// tightened by the quota it was pulled with, along with the quota shared by the programs of
+    /// its canister.
+    fn limiter(&self, metadata: &ProgramMetadata) -> Limiter {
+        let mut quota = self.config.quota(&metadata.canister_id);
+        if let Some(pulled) = &metadata.quota {
+            quota = quota.tightest(pulled);
+        }
+        Limiter::new(quota, self.canister_quotas.limiter(&metadata.canister_id))
+    }
+
+    /// Calls the procedure of a loaded program with the candid encoded payload. The call is
+    /// interrupted at the timeout, or at the program's call timeout when that is shorter.
+    ///
+    /// Calls over the quota of the program or of its canister are turned away with
+    /// [`Error::RateLimited`].
+    pub async fn call_procedure(
+        &self,
+        program_id: &ProgramId,
+        procedure: &str,
+        payload: &[u8],
+        timeout: Option<Duration>,
+    ) -> HarnessResult<Invocation> {
+        // the pool is taken out so that the lock is not held during the call
+        let (program, limiter, operation) = {
+            let programs = self.programs.read().await;
+            // the procedure comes from the caller, only the declared ones get series of their own
+            let operation = if programs.declares(program_id, procedure) {
+                procedure
+            } else {
+                metrics::UNKNOWN_OPERATION
+            };
+            (
+                programs.pool(program_id),
+                programs.limiter(program_id),
+                operation,
+            )
+        };
+
+        let (Some(program), Some(limiter)) = (program, limiter) else {
+            return Err(Error::NotFound {
+                message: format!("the program '{}' is not loaded", program_id.as_str()),
+            });
+        };
+
+        let _permit = limiter.admit().map_err(|throttled| {
+            self.metrics
+                .observe_throttled(program_id.as_str(), operation);
+            let quota = match throttled.scope {
+                QuotaScope::Program => "its quota",
+                QuotaScope::Canister => "the quota of its canister",
+            };
+            Error::RateLimited {
+                message: format!(
+                    "the calls into the program '{}' are over {quota} of {} {}",
+                    program_id.as_str(),
+                    throttled.max,
+                    throttled.limit
+                ),
+                retry_after: throttled.retry_after,
+            }
+        })?;
+
+        let started = Instant::now();
+        let result = program.invoke(procedure, payload, timeout).await;
+        self.metrics.observe_call(
+            program_id.as_str(),
+            operation,
+            payload.len(),
+            started.elapsed(),
+            &result,
+        );
+        result
+    }
+
+    /// Pulls the program on the first call its canister routes to the device, when the canister
+    /// is configured with `load_on_demand`. Noop when the program is loaded or the programs of
+    /// the canister are not loaded on demand.
+    ///
+    /// A canister serves a single program, a call into another program of a canister whose
+    /// program is loaded pulls nothing and fails as any call into a program that is not loaded.
+    pub async fn load_on_demand(
         &self,
+        program_id: &ProgramId,
         canister_id: &str,
-        icp_url: &str,
-    ) -> impl Future<Output = core::result::Result<Vec<u8>, AgentError>>;
-}
+    ) -> HarnessResult<()> {
+        let Some(canister) = self.on_demand_canister(canister_id) else {
+            return Ok(());
+        };
+        let is_loaded = |programs: &Programs| {
+            programs.pool(program_id).is_some() || programs.serves_canister(canister_id)
+        };
+        if is_loaded(&*self.programs.read().await) {
+            return Ok(());
+        }
+
+        // the calls that raced the first one find the program loaded
+        let _loading = self.loading.lock().await;
+        if is_loaded(&*self.programs.read().await) {
+            return Ok(());
+        }
 
-/// This is the implementation of the ICP agent.
-pub struct IcpAgentImpl;
+        let mut program = PullProgram::from(canister);
+        program.program_id = Some(program_id.as_str().to_string());
+        let pulled = self.pull_program(program).await?;
+        tracing::info!(
+            program_id = pulled.program_id,
+            canister_id,
+            sha256 = pulled.sha256,
+            "pulled program on demand"
+        );
+        Ok(())
+    }
+
+    /// The configured canister whose program is loaded on demand.
+    fn on_demand_canister(&self, canister_id: &str) -> Option<&CanisterConfig> {
+        self.config
+            .canister(canister_id)
+            .filter(|canister| canister.load_on_demand)
+    }
 
-impl IcpAgent for IcpAgentImpl {
-    async fn get_program_code(
+    /// Verifies that the procedure call into the program is signed by its canister, with the
+    /// secret the canister issued to the device, and was made within the signature window.
+    ///
+    /// A call into a program that is not loaded yet is verified with the secret of its canister
+    /// when the program of the canister is loaded on demand and not loaded yet, so that only the
+    /// canister can have it pulled.
+    pub async fn verify_call(
         &self,
+        program_id: &ProgramId,
         canister_id: &str,
-        icp_url: &str,
-    ) -> core::result::Result<Vec<u8>, AgentError> {
-        let agent = Agent::builder().with_url(icp_url).build().unwrap();
-        agent.fetch_root_key().await?;
-
-        let response = agent
-            .query(
-                &Principal::from_text(canister_id).unwrap(),
-                "get_program_code",
-            )
-            .with_arg(candid::encode_one(()).unwrap())
-            .call()
-            .await?;
+        request: &SignedRequest<'_>,
+        signature: &str,
+    ) -> HarnessResult<()> {
+        let programs = self.programs.read().await;
+        let mut secrets = programs.device_secrets(program_id, self.config.signature_max_age());
+        if secrets.is_empty() && !programs.serves_canister(canister_id) {
+            if let Some(canister) = self.on_demand_canister(canister_id) {
+                secrets.extend(
+                    canister
+                        .device_secret
+                        .clone()
+                        .or_else(|| self.registered_secret(canister_id)),
+                );
+            }
+        }
+        drop(programs);
+        if secrets.is_empty() {
+            return Err(Error::Unauthorized {
+                message: format!(
+                    "the device holds no secret to verify calls into the program '{}'",
+                    program_id.as_str()
+                ),
+            });
+        }
 
-        Ok(Decode!(&response, Vec<u8>).unwrap())
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        if u128::from(now.abs_diff(request.timestamp)) > self.config.signature_max_age().as_nanos()
+        {
+            return Err(Error::Unauthorized {
+                message: "the call was not signed within the signature window".to_string(),
+            });
+        }
+
+        // the secret replaced when the device registered again verifies the calls signed before
+        let mut verified = false;
+        for secret in secrets {
+            verified |= request.verify(&decode_secret(&secret)?, signature);
+        }
+        if !verified {
+            return Err(Error::Unauthorized {
+                message: "the call signature is invalid".to_string(),
+            });
+        }
+        Ok(())
     }
-}
 
-impl<T: IcpAgent> NodeServer<T> {
-    pub async fn handler(&mut self, req: Request) -> HarnessResult<Response<Cursor<Vec<u8>>>> {
-        match (Method::try_from(req.method.as_str())?, req.path.as_str()) {
-            (Method::GET, "/hello") => Ok(Response::hello()),
-
-            // TODO: rework with tests and proper implementation
-            (Method::POST, "/program") => {
-                let program = match serde_json::from_slice::<PullProgram>(&req.data) {
-                    Ok(programs) => programs,
-                    Err(err) => {
-                        return Ok(Response {
-                            status_code: 400,
-                            data: Cursor::new(err.to_string().into_bytes()),
-                            headers: vec![],
-                        })
-                    }
-                };
-
-                let response = self
-                    .icp_agent
-                    .get_program_code(&program.canister_id, &program.url)
-                    .await
-                    .unwrap();
-
-                self.harness_os
-                    .add_program(program.program_id.parse()?, &response)
-                    .await?;
-
-                Ok(Response {
-                    status_code: 202,
-                    data: Cursor::new(vec![]),
-                    headers: vec![],
-                })
+    /// Removes the program and its stored copy from the device, noop if it is not loaded.
+    pub async fn remove_program(&self, program_id: &ProgramId) -> HarnessResult<()> {
+        let _program = self.lock_program(program_id).await;
+        self.programs.write().await.unload(program_id);
+        self.store.remove(program_id).await
+    }
+
+    /// Serializes the changes to the program, so that its stored copy and its loaded version
+    /// change together without holding the programs lock during IO.
+    async fn lock_program(&self, program_id: &ProgramId) -> tokio::sync::OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self
+                .program_locks
+                .lock()
+                .expect("lock is not poisoned; qed");
+            // the locks no one holds or waits for are dropped
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            locks.entry(program_id.clone()).or_default().clone()
+        };
+        lock.lock_owned().await
+    }
+
+    /// Describes the programs loaded to the device.
+    pub async fn programs(&self) -> Vec<ProgramInfo> {
+        self.programs.read().await.infos()
+    }
+
+    /// Renders the node metrics in the Prometheus text format.
+    pub async fn metrics(&self) -> String {
+        let programs = self.programs().await;
+        self.metrics.render(&programs)
+    }
+
+    /// Reports whether the node can accept work. It cannot when it is shutting down, when pulled
+    /// programs cannot be stored or when every instance of the loaded programs is serving a call.
+    ///
+    /// The data directory is probed on the first report and after storing a program failed.
+    pub async fn readiness(&self) -> Readiness {
+        let (programs, pool) = {
+            let programs = self.programs.read().await;
+            (programs.len(), programs.pool_usage())
+        };
+        let data_dir_writable = self.store.is_writable().await;
+
+        let draining = self.is_draining();
+        let pending_pulls = self.pending_pulls.load(Ordering::Relaxed);
+
+        Readiness {
+            ready: !draining && pending_pulls == 0 && data_dir_writable && !pool.saturated(),
+            draining,
+            pending_pulls,
+            version: env!("CARGO_PKG_VERSION"),
+            protocol_version: PROTOCOL_VERSION,
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            programs,
+            pool,
+            data_dir_writable,
+        }
+    }
+
+    /// Marks the node as shutting down, readiness then reports that it cannot accept work so that
+    /// load balancers and canisters move on while in-flight calls finish.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the node is shutting down.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Registers the device and pulls the programs of the configured canisters in the background,
+    /// those loaded on demand are pulled on the first call of their canister instead.
+    ///
+    /// Readiness reports the pulls still pending, from the moment they are spawned. A program
+    /// that fails to be pulled is logged and skipped.
+    pub fn spawn_startup_pulls(self: Arc<Self>) -> JoinHandle<()>
+    where
+        T: Send + Sync + 'static,
+    {
+        let canisters = self
+            .config
+            .canisters
+            .iter()
+            .filter(|canister| !canister.load_on_demand)
+            .cloned()
+            .collect::<Vec<_>>();
+        self.pending_pulls.store(canisters.len(), Ordering::Relaxed);
+
+        tokio::spawn(async move {
+            // the configured programs are pulled with the secrets issued on registration
+            self.register_device().await;
+            for canister in canisters {
+                if let Err(err) = self.pull_program((&canister).into()).await {
+                    tracing::warn!(
+                        program_id = canister.program_id,
+                        canister_id = canister.canister_id,
+                        error = %err,
+                        "failed to pull program"
+                    );
+                }
+                self.pending_pulls.fetch_sub(1, Ordering::Relaxed);
             }
+        })
+    }
 
-            (Method::POST, "/procedure") => {
-                println!("Headers: {:?}", req.headers);
-
-                let program_id = match get_header(&Header::ProgramId.to_string(), &req.headers) {
-                    Some(program_id) => program_id,
-                    None => {
-                        return Ok(Response {
-                            status_code: 400,
-                            data: Cursor::new(
-                                "Program-Identifier header could not be retrieved".into(),
-                            ),
-                            headers: vec![],
-                        });
-                    }
-                };
-
-                let program_id = program_id.trim().to_string();
-
-                let procedure = match get_header(&Header::ProgramProc.to_string(), &req.headers) {
-                    Some(procedure) => procedure,
-                    None => {
-                        return Ok(Response {
-                            status_code: 400,
-                            data: Cursor::new(
-                                "Program-Procedure header could not be retrieved".into(),
-                            ),
-                            headers: vec![],
-                        })
-                    }
-                };
-
-                println!("Program-ids: {:?}", self.harness_os.program_ids());
-
-                match self
-                    .harness_os
-                    .call_operation(&program_id.parse()?, procedure.trim(), &req.data)
-                    .await
-                {
-                    Ok(res) => Ok(Response {
-                        status_code: 200,
-                        data: Cursor::new(res),
-                        headers: vec![],
-                    }),
+    /// Registers the `device_url` with every configured canister, the secret each canister
+    /// issues replaces the one the calls into its programs are verified with. Noop unless
+    /// `register_on_startup` is set.
+    ///
+    /// A canister that cannot be reached is skipped, the registration is tried again on the next
+    /// renewal.
+    pub async fn register_device(&self) {
+        let Some(device_url) = self
+            .config
+            .device_url
+            .as_deref()
+            .filter(|_| self.config.register_on_startup)
+        else {
+            return;
+        };
+
+        for canister in &self.config.canisters {
+            let canister_id = canister.canister_id.as_str();
+            let registered = self
+                .icp_agent
+                .register_device(canister_id, &canister.url, device_url)
+                .await;
+            match registered {
+                Ok(secret) => match self.adopt_secret(canister_id, secret).await {
+                    Ok(()) => tracing::info!(canister_id, "registered the device"),
                     Err(err) => {
-                        eprintln!("{err}");
-                        Ok(Response {
-                            status_code: 400,
-                            data: Cursor::new(err.to_string().into_bytes()),
-                            headers: vec![],
-                        })
+                        tracing::warn!(canister_id, error = %err, "failed to store the device secret")
                     }
+                },
+                Err(err) => {
+                    tracing::warn!(canister_id, error = %err, "failed to register the device")
                 }
             }
+        }
+    }
 
-            (Method::DELETE, "/program") => {
-                let program_id =
-                    get_header(&Header::ProgramId.to_string(), &req.headers).ok_or(Error::IO {
-                        message: "Program-Identifier header could not be retrieved".to_string(),
-                        inner: None,
-                    })?;
-
-                self.harness_os.remove_program(&program_id.parse()?);
+    /// Renews the registrations made on startup every `registration_renewal_ms`, until the node
+    /// drains. Returns at once when the registrations are not renewed.
+    pub async fn renew_registrations(&self) {
+        let Some(period) = self
+            .config
+            .registration_renewal()
+            .filter(|_| self.config.register_on_startup)
+        else {
+            return;
+        };
 
-                Ok(Response {
-                    status_code: 204,
-                    data: Cursor::new(vec![]),
-                    headers: vec![],
-                })
+        let mut renewal = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        renewal.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            renewal.tick().await;
+            if self.is_draining() {
+                return;
             }
+            self.register_device().await;
+        }
+    }
+
+    /// The secret issued when the device registered with the canister, if it did.
+    fn registered_secret(&self, canister_id: &str) -> Option<String> {
+        self.registrations
+            .lock()
+            .expect("lock is not poisoned; qed")
+            .get(canister_id)
+            .cloned()
+    }
+
+    /// Verifies the calls of the canister with the secret it issued from now on, the programs
+    /// pulled from it are stored again with the new secret.
+    async fn adopt_secret(&self, canister_id: &str, secret: String) -> HarnessResult<()> {
+        decode_secret(&secret)?;
+        let rotated = self
+            .programs
+            .write()
+            .await
+            .rotate_secret(canister_id, &secret);
+        self.registrations
+            .lock()
+            .expect("lock is not poisoned; qed")
+            .insert(canister_id.to_string(), secret);
+
+        for metadata in rotated {
+            self.store.save_metadata(&metadata).await?;
+        }
+        Ok(())
+    }
 
-            (_, _) => Ok(Response {
-                status_code: 404,
-                data: Cursor::new(vec![]),
-                headers: vec![],
-            }),
+    /// Finishes the node once the connections are drained. Waits for pulled programs being
+    /// swapped in, then deregisters the device from the canisters of the loaded programs when
+    /// `deregister_on_shutdown` is set, and from the configured canisters when the device
+    /// registered with them on startup.
+    pub async fn shutdown(&self) {
+        self.drain();
+        let mut canisters = {
+            // taking exclusive access waits for the programs being swapped in
+            let programs = self.programs.write().await;
+            programs
+                .infos()
+                .into_iter()
+                .filter(|_| self.config.deregister_on_shutdown)
+                .map(|program| (program.canister_id, program.url))
+                .collect::<BTreeSet<_>>()
+        };
+        if self.config.register_on_startup {
+            canisters.extend(
+                self.config
+                    .canisters
+                    .iter()
+                    .map(|canister| (canister.canister_id.clone(), canister.url.clone())),
+            );
         }
+
+        let Some(device_url) = self.config.device_url.as_deref() else {
+            return;
+        };
+
+        for (canister_id, url) in canisters {
+            match self
+                .icp_agent
+                .remove_device(&canister_id, &url, device_url)
+                .await
+            {
+                Ok(()) => tracing::info!(canister_id, "deregistered the device"),
+                Err(err) => {
+                    tracing::warn!(canister_id, error = %err, "failed to deregister the device")
+                }
+            }
+        }
+    }
+
+    /// Describes a loaded program.
+    pub async fn program(&self, program_id: &ProgramId) -> HarnessResult<ProgramInfo> {
+        self.programs
+            .read()
+            .await
+            .info(program_id)
+            .ok_or_else(|| Error::NotFound {
+                message: format!("the program '{}' is not loaded", program_id.as_str()),
+            })
     }
 }
 
-/// Starts a server on a random port and returns the port and the listener.
-pub async fn start_server() -> HarnessResult<(u16, TcpListener)> {
-    let port_ = std::env::var("HARNESS_PORT").unwrap_or_else(|_| String::from("0"));
-    let listener = TcpListener::bind(SocketAddrV4::new(
-        Ipv4Addr::LOCALHOST,
-        port_.parse::<u16>().unwrap(),
-    ))
-    .await
-    .map_err(|err| Error::io("failed to bind to a port", err.into()))?;
+/// Binds the address and returns the port and the listener, port `0` binds a random port.
+pub async fn start_server(addr: SocketAddr) -> HarnessResult<(u16, TcpListener)> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|err| Error::io(&format!("failed to bind to {addr}"), err.into()))?;
 
     let port = listener
         .local_addr()
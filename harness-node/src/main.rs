@@ -1,30 +1,230 @@
-use harness_node::{new_node_server, start_server, IcpAgentImpl};
-use tokio::io::BufStream;
+use std::{future::IntoFuture, net::IpAddr, path::PathBuf, sync::Arc};
 
-use harness_primitives::http::parse_request;
+use clap::{ArgAction, Parser};
+
+use harness_node::{
+    config::{Config, LogFormat, LogLevel, Network},
+    new_node_server_with_config, start_server, telemetry, IcpAgentImpl,
+};
+
+/// Runs a harness node, serving the programs pulled from IC canisters.
+///
+/// Flags take precedence over the config file, which takes precedence over the defaults.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Cli {
+    /// The TOML config file of the node.
+    #[arg(short, long, env = "HARNESS_CONFIG")]
+    config: Option<PathBuf>,
+    /// The address to listen on, IPv4 or IPv6.
+    #[arg(short, long)]
+    bind_address: Option<IpAddr>,
+    /// The port to listen on, `0` picks a random port.
+    #[arg(short, long, env = "HARNESS_PORT")]
+    port: Option<u16>,
+    /// The directory where the node keeps its state.
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+    /// The verbosity of the node logs.
+    #[arg(long, value_enum)]
+    log_level: Option<LogLevel>,
+    /// How the node logs are written.
+    #[arg(long, value_enum)]
+    log_format: Option<LogFormat>,
+    /// The largest request body accepted, in bytes.
+    #[arg(long)]
+    max_request_size: Option<usize>,
+    /// The address the admin listener binds to, IPv4 or IPv6.
+    #[arg(long, env = "HARNESS_ADMIN_BIND")]
+    admin_bind: Option<IpAddr>,
+    /// The port of the admin listener serving the program management routes.
+    #[arg(long, env = "HARNESS_ADMIN_PORT")]
+    admin_port: Option<u16>,
+    /// The bearer token required by the admin routes.
+    #[arg(long, env = "HARNESS_ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+    /// The URL the canisters reach the node at.
+    #[arg(long, env = "HARNESS_DEVICE_URL")]
+    device_url: Option<String>,
+    /// Whether the device registers with the configured canisters on startup, renews the
+    /// registrations while running and deregisters on shutdown.
+    #[arg(long, action = ArgAction::Set, value_name = "BOOL")]
+    register_on_startup: Option<bool>,
+    /// The IC network of the canisters that do not configure their own.
+    #[arg(long, value_enum, env = "HARNESS_NETWORK")]
+    network: Option<Network>,
+    /// The hex encoded DER root key of a `custom` network.
+    #[arg(long, env = "HARNESS_ROOT_KEY")]
+    root_key: Option<String>,
+    /// The PEM file of the identity the node calls the canisters with.
+    #[arg(long, env = "HARNESS_IDENTITY_PEM")]
+    identity_pem: Option<PathBuf>,
+}
+
+impl Cli {
+    /// Reads the config file, if any, and applies the flags on top of it before validating the
+    /// result.
+    fn into_config(self) -> harness_primitives::error::Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+
+        if let Some(bind_address) = self.bind_address {
+            config.bind_address = bind_address;
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(data_dir) = self.data_dir {
+            config.data_dir = data_dir;
+        }
+        if let Some(log_level) = self.log_level {
+            config.log_level = log_level;
+        }
+        if let Some(log_format) = self.log_format {
+            config.log_format = log_format;
+        }
+        if let Some(max_request_size) = self.max_request_size {
+            config.max_request_size = max_request_size;
+        }
+        if let Some(bind_address) = self.admin_bind {
+            config.admin.bind_address = bind_address;
+        }
+        if let Some(port) = self.admin_port {
+            config.admin.port = port;
+        }
+        if let Some(token) = self.admin_token {
+            config.admin.token = Some(token);
+        }
+        if let Some(device_url) = self.device_url {
+            config.device_url = Some(device_url);
+        }
+        if let Some(register_on_startup) = self.register_on_startup {
+            config.register_on_startup = register_on_startup;
+        }
+        if let Some(network) = self.network {
+            config.network = network;
+        }
+        if let Some(root_key) = self.root_key {
+            config.root_key = Some(root_key);
+        }
+        if let Some(identity_pem) = self.identity_pem {
+            config.identity_pem = Some(identity_pem);
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    let (port, listener) = start_server().await?;
-    println!("connect on port '{port}'"); // todo: do telemetry properly
-
-    let mut server = new_node_server(IcpAgentImpl);
-    loop {
-        let (stream, _) = listener.accept().await?;
-        let mut stream = BufStream::new(stream);
-
-        // the wasm engine is single-threaded, todo: allow async ops by replication
-        // also multiple programs so really should not be sequential here
-        match parse_request(&mut stream).await {
-            Ok(req) => {
-                let resp = server.handler(req).await.unwrap_or_else(|e| e.into());
-                if let Err(err) = resp.write(&mut stream).await {
-                    println!("{err}")
-                }
-            }
+    let config = Cli::parse().into_config()?;
+    telemetry::init(config.log_level, config.log_format);
+
+    let (port, listener) = start_server(config.socket_addr()).await?;
+    tracing::info!(address = %config.bind_address, port, "listening");
+    let (admin_port, admin_listener) = start_server(config.admin.socket_addr()).await?;
+    tracing::info!(
+        address = %config.admin.bind_address,
+        port = admin_port,
+        "admin listening"
+    );
+
+    let agent = IcpAgentImpl::new(&config)?;
+    tracing::info!(principal = %agent.principal(), "node identity");
+    let server = Arc::new(new_node_server_with_config(agent, config));
+    let restored = server.restore_programs().await?;
+    tracing::info!(programs = restored.len(), "restored stored programs");
+
+    // the listeners stop accepting connections once the signal is received, then wait for the
+    // in-flight requests to be served
+    let (stop, stopped) = tokio::sync::watch::channel(());
+    let mut listeners = tokio::task::JoinSet::new();
+    let mut serve = |listener, router| {
+        let mut stopped = stopped.clone();
+        listeners.spawn(
+            axum::serve(listener, router)
+                .with_graceful_shutdown(async move {
+                    _ = stopped.changed().await;
+                })
+                .into_future(),
+        );
+    };
+    serve(listener, server.clone().public_router());
+    serve(admin_listener, server.clone().admin_router());
+
+    // the node serves, reporting itself not ready, while the configured programs are pulled
+    let pulls = server.clone().spawn_startup_pulls();
+    let renewals = tokio::spawn({
+        let server = server.clone();
+        async move { server.renew_registrations().await }
+    });
+
+    // a listener only stops on its own when it fails, which stops the node
+    let failed = tokio::select! {
+        _ = shutdown_signal() => None,
+        Some(served) = listeners.join_next() => {
+            let err = match served {
+                Ok(Ok(())) => anyhow::anyhow!("a listener stopped"),
+                Ok(Err(err)) => err.into(),
+                Err(err) => err.into(),
+            };
+            tracing::error!(error = %err, "a listener failed, shutting down");
+            Some(err)
+        }
+    };
+    tracing::info!(
+        timeout_ms = server.config().shutdown_timeout_ms,
+        "shutting down, draining in-flight calls"
+    );
+    server.drain();
+    pulls.abort();
+    renewals.abort();
+    _ = stop.send(());
+
+    let serving = async {
+        while let Some(served) = listeners.join_next().await {
+            served??;
+        }
+        Ok::<_, anyhow::Error>(())
+    };
+    match tokio::time::timeout(server.config().shutdown_timeout(), serving).await {
+        Ok(served) => served?,
+        Err(_) => tracing::warn!("the drain deadline passed, aborting the remaining calls"),
+    }
+
+    server.shutdown().await;
+    tracing::info!("shut down");
+
+    failed.map_or(Ok(()), Err)
+}
+
+/// Resolves once the node is asked to stop, by `SIGINT` or by `SIGTERM` on unix.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            tracing::error!(error = %err, "failed to listen for SIGINT");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => _ = terminate.recv().await,
             Err(err) => {
-                eprintln!("{err}")
+                tracing::error!(error = %err, "failed to listen for SIGTERM");
+                std::future::pending::<()>().await;
             }
         }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
     }
 }
@@ -0,0 +1,217 @@
+//! The Prometheus metrics of the node, served by `GET /metrics`.
+use std::time::Duration;
+
+use prometheus::{
+    exponential_buckets, Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+use harness_primitives::{error::Error, harness_os::Invocation};
+
+use crate::inventory::ProgramInfo;
+
+/// The operation label of the calls into procedures the schema of the program does not declare,
+/// so that callers cannot create series at will.
+pub const UNKNOWN_OPERATION: &str = "unknown";
+
+/// The metrics recorded by the node, labelled by program and by the operation called into it.
+pub struct Metrics {
+    registry: Registry,
+    invocations: IntCounterVec,
+    failures: IntCounterVec,
+    throttled: IntCounterVec,
+    latency: HistogramVec,
+    request_size: HistogramVec,
+    response_size: HistogramVec,
+    fuel_consumed: HistogramVec,
+    load_duration: HistogramVec,
+    pool_size: IntGaugeVec,
+    pool_in_use: IntGaugeVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("harness".to_string()), None)
+            .expect("the prefix is a valid metric name; qed");
+        let size_buckets = exponential_buckets(64.0, 4.0, 10).expect("buckets are valid; qed");
+
+        let metrics = Self {
+            invocations: IntCounterVec::new(
+                Opts::new("invocations_total", "Calls made into a program operation."),
+                &["program", "operation"],
+            )
+            .expect("metric is valid; qed"),
+            failures: IntCounterVec::new(
+                Opts::new(
+                    "failures_total",
+                    "Calls into a program operation that failed, by error kind.",
+                ),
+                &["program", "operation", "kind"],
+            )
+            .expect("metric is valid; qed"),
+            throttled: IntCounterVec::new(
+                Opts::new(
+                    "throttled_total",
+                    "Calls into a program operation turned away by a quota.",
+                ),
+                &["program", "operation"],
+            )
+            .expect("metric is valid; qed"),
+            latency: HistogramVec::new(
+                HistogramOpts::new(
+                    "call_duration_seconds",
+                    "Time taken by a call into a program operation, waiting for an instance included.",
+                ),
+                &["program", "operation"],
+            )
+            .expect("metric is valid; qed"),
+            request_size: HistogramVec::new(
+                HistogramOpts::new(
+                    "request_payload_bytes",
+                    "Size of the payloads passed to a program operation.",
+                )
+                .buckets(size_buckets.clone()),
+                &["program", "operation"],
+            )
+            .expect("metric is valid; qed"),
+            response_size: HistogramVec::new(
+                HistogramOpts::new(
+                    "response_payload_bytes",
+                    "Size of the payloads returned by a program operation.",
+                )
+                .buckets(size_buckets),
+                &["program", "operation"],
+            )
+            .expect("metric is valid; qed"),
+            fuel_consumed: HistogramVec::new(
+                HistogramOpts::new(
+                    "fuel_consumed",
+                    "Fuel consumed by a call into a program operation.",
+                )
+                .buckets(exponential_buckets(1_000.0, 10.0, 8).expect("buckets are valid; qed")),
+                &["program", "operation"],
+            )
+            .expect("metric is valid; qed"),
+            load_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "program_load_duration_seconds",
+                    "Time taken to compile a program and create its instances.",
+                ),
+                &["program"],
+            )
+            .expect("metric is valid; qed"),
+            pool_size: IntGaugeVec::new(
+                Opts::new("pool_instances", "Instances created for a program."),
+                &["program"],
+            )
+            .expect("metric is valid; qed"),
+            pool_in_use: IntGaugeVec::new(
+                Opts::new(
+                    "pool_instances_in_use",
+                    "Instances of a program that are serving a call.",
+                ),
+                &["program"],
+            )
+            .expect("metric is valid; qed"),
+            registry,
+        };
+
+        for collector in [
+            Box::new(metrics.invocations.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(metrics.failures.clone()),
+            Box::new(metrics.throttled.clone()),
+            Box::new(metrics.latency.clone()),
+            Box::new(metrics.request_size.clone()),
+            Box::new(metrics.response_size.clone()),
+            Box::new(metrics.fuel_consumed.clone()),
+            Box::new(metrics.load_duration.clone()),
+            Box::new(metrics.pool_size.clone()),
+            Box::new(metrics.pool_in_use.clone()),
+        ] {
+            metrics
+                .registry
+                .register(collector)
+                .expect("metric names are unique; qed");
+        }
+
+        metrics
+    }
+
+    /// Records a call into a program operation.
+    pub fn observe_call(
+        &self,
+        program: &str,
+        operation: &str,
+        payload_size: usize,
+        elapsed: Duration,
+        result: &Result<Invocation, Error>,
+    ) {
+        let labels = [program, operation];
+        self.invocations.with_label_values(&labels).inc();
+        self.latency
+            .with_label_values(&labels)
+            .observe(elapsed.as_secs_f64());
+        self.request_size
+            .with_label_values(&labels)
+            .observe(payload_size as f64);
+
+        match result {
+            Ok(invocation) => {
+                self.response_size
+                    .with_label_values(&labels)
+                    .observe(invocation.response.len() as f64);
+                if let Some(fuel_consumed) = invocation.fuel_consumed {
+                    self.fuel_consumed
+                        .with_label_values(&labels)
+                        .observe(fuel_consumed as f64);
+                }
+            }
+            Err(err) => self
+                .failures
+                .with_label_values(&[program, operation, err.kind()])
+                .inc(),
+        }
+    }
+
+    /// Records a call into a program operation turned away by a quota.
+    pub fn observe_throttled(&self, program: &str, operation: &str) {
+        self.throttled
+            .with_label_values(&[program, operation])
+            .inc();
+    }
+
+    /// Records the time taken to load a program.
+    pub fn observe_load(&self, program: &str, elapsed: Duration) {
+        self.load_duration
+            .with_label_values(&[program])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Renders the metrics in the Prometheus text format, the pool gauges are set from the
+    /// programs loaded at the time.
+    pub fn render(&self, programs: &[ProgramInfo]) -> String {
+        self.pool_size.reset();
+        self.pool_in_use.reset();
+        for program in programs {
+            let labels = [program.program_id.as_str()];
+            self.pool_size
+                .with_label_values(&labels)
+                .set(program.pool_size as i64);
+            self.pool_in_use
+                .with_label_values(&labels)
+                .set(program.in_use as i64);
+        }
+
+        let mut buf = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .expect("the text encoding does not fail; qed");
+        String::from_utf8(buf).expect("the text encoding is utf-8; qed")
+    }
+}
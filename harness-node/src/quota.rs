@@ -0,0 +1,326 @@
+//! The call quotas of the loaded programs, so that a busy canister cannot take over a device
+//! shared with others. Calls over a quota are turned away with the time after which the caller
+//! may retry, they are not queued.
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use harness_primitives::program::CallQuota;
+
+/// How long a caller turned away for running too many calls at once is asked to wait, there is
+/// no telling when a running call finishes.
+const CONCURRENCY_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Admits the calls within a [`CallQuota`], along with the quota of the canister when it is
+/// shared with other programs.
+pub struct Limiter {
+    quota: CallQuota,
+    bucket: Option<Mutex<Bucket>>,
+    running: AtomicU32,
+    throttled: AtomicU64,
+    shared: Option<Arc<Limiter>>,
+}
+
+/// Why a call was turned away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Throttled {
+    /// The quota the call is over.
+    pub scope: QuotaScope,
+    /// The limit the call is over.
+    pub limit: QuotaLimit,
+    /// The value of the limit, in calls per second or concurrent calls.
+    pub max: u32,
+    /// When the call may be retried.
+    pub retry_after: Duration,
+}
+
+/// The quotas a call is admitted by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaScope {
+    /// The quota of the program.
+    Program,
+    /// The quota shared by the programs of its canister.
+    Canister,
+}
+
+/// The limits of a [`CallQuota`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaLimit {
+    Rate,
+    Concurrency,
+}
+
+impl Display for QuotaLimit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Rate => write!(f, "calls per second"),
+            Self::Concurrency => write!(f, "concurrent calls"),
+        }
+    }
+}
+
+/// Holds a place among the concurrent calls of the quota until the call finishes.
+pub struct Permit {
+    limiter: Arc<Limiter>,
+    _shared: Option<Box<Permit>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if self.limiter.quota.max_concurrent_calls.is_some() {
+            self.limiter.running.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+impl Limiter {
+    /// Creates the limiter of the quota, calls must also be admitted by the `shared` limiter.
+    pub fn new(quota: CallQuota, shared: Option<Arc<Limiter>>) -> Self {
+        let bucket = quota
+            .rate_per_sec
+            .filter(|rate| *rate != 0)
+            .map(|rate| Mutex::new(Bucket::new(rate, quota.burst.unwrap_or(rate))));
+
+        Self {
+            quota,
+            bucket,
+            running: AtomicU32::new(0),
+            throttled: AtomicU64::new(0),
+            shared,
+        }
+    }
+
+    /// The quota enforced by the limiter.
+    pub fn quota(&self) -> &CallQuota {
+        &self.quota
+    }
+
+    /// The number of calls turned away since the limiter was created.
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+
+    /// Admits a call, the permit is held for as long as the call runs.
+    pub fn admit(self: &Arc<Self>) -> Result<Permit, Throttled> {
+        self.acquire().inspect_err(|_| {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+        })
+    }
+
+    fn acquire(self: &Arc<Self>) -> Result<Permit, Throttled> {
+        let Some(shared) = &self.shared else {
+            return self.acquire_own(None);
+        };
+
+        let permit = Box::new(shared.acquire().map_err(|throttled| Throttled {
+            scope: QuotaScope::Canister,
+            ..throttled
+        })?);
+        // a call turned away by the program's own quota does not count against the canister's
+        self.acquire_own(Some(permit))
+            .inspect_err(|_| shared.refund())
+    }
+
+    fn acquire_own(self: &Arc<Self>, shared: Option<Box<Permit>>) -> Result<Permit, Throttled> {
+        if let Some(max) = self.quota.max_concurrent_calls {
+            self.running
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |running| {
+                    (running < max).then_some(running + 1)
+                })
+                .map_err(|_| Throttled {
+                    scope: QuotaScope::Program,
+                    limit: QuotaLimit::Concurrency,
+                    max,
+                    retry_after: CONCURRENCY_RETRY_AFTER,
+                })?;
+        }
+        // from here on the permit gives the place back when the call is turned away
+        let permit = Permit {
+            limiter: self.clone(),
+            _shared: shared,
+        };
+
+        if let Some(bucket) = &self.bucket {
+            let mut bucket = bucket.lock().expect("lock is not poisoned; qed");
+            bucket
+                .take(Instant::now())
+                .map_err(|retry_after| Throttled {
+                    scope: QuotaScope::Program,
+                    limit: QuotaLimit::Rate,
+                    max: self.quota.rate_per_sec.unwrap_or_default(),
+                    retry_after,
+                })?;
+        }
+
+        Ok(permit)
+    }
+
+    /// Gives back the token taken by a call that was turned away after all.
+    fn refund(&self) {
+        if let Some(bucket) = &self.bucket {
+            bucket.lock().expect("lock is not poisoned; qed").refund();
+        }
+        if let Some(shared) = &self.shared {
+            shared.refund();
+        }
+    }
+}
+
+/// A token bucket refilled at a steady rate, a call takes one token.
+struct Bucket {
+    tokens: f64,
+    capacity: f64,
+    rate: f64,
+    refilled_at: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u32, burst: u32) -> Self {
+        let capacity = f64::from(burst.max(1));
+        Self {
+            tokens: capacity,
+            capacity,
+            rate: f64::from(rate),
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Takes a token, returning how long until one is available when the bucket is empty.
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.capacity);
+        self.refilled_at = now;
+
+        if self.tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate));
+        }
+        self.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Puts back a token taken by [`Bucket::take`].
+    fn refund(&mut self) {
+        self.tokens = (self.tokens + 1.0).min(self.capacity);
+    }
+}
+
+/// The limiters shared by the programs pulled from the same canister.
+pub(crate) struct CanisterQuotas {
+    quota: CallQuota,
+    limiters: Mutex<HashMap<String, Arc<Limiter>>>,
+}
+
+impl CanisterQuotas {
+    pub fn new(quota: CallQuota) -> Self {
+        Self {
+            quota,
+            limiters: Mutex::default(),
+        }
+    }
+
+    /// The limiter shared by the programs of the canister, `None` when no quota is configured.
+    pub fn limiter(&self, canister_id: &str) -> Option<Arc<Limiter>> {
+        if self.quota.is_unlimited() {
+            return None;
+        }
+
+        let mut limiters = self.limiters.lock().expect("lock is not poisoned; qed");
+        let limiter = limiters
+            .entry(canister_id.to_string())
+            .or_insert_with(|| Arc::new(Limiter::new(self.quota.clone(), None)));
+        Some(limiter.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_refills_at_its_rate() {
+        let mut bucket = Bucket::new(10, 2);
+        let now = bucket.refilled_at;
+
+        assert!(bucket.take(now).is_ok());
+        assert!(bucket.take(now).is_ok());
+        assert_eq!(bucket.take(now), Err(Duration::from_millis(100)));
+
+        // the tokens refilled never exceed the burst
+        let later = now + Duration::from_secs(10);
+        assert!(bucket.take(later).is_ok());
+        assert!(bucket.take(later).is_ok());
+        assert!(bucket.take(later).is_err());
+    }
+
+    #[test]
+    fn test_limiter_enforces_the_shared_quota() {
+        let canister = Arc::new(Limiter::new(
+            CallQuota {
+                max_concurrent_calls: Some(1),
+                ..Default::default()
+            },
+            None,
+        ));
+        let program = Arc::new(Limiter::new(CallQuota::default(), Some(canister.clone())));
+        let other = Arc::new(Limiter::new(CallQuota::default(), Some(canister)));
+
+        let permit = program.admit().unwrap();
+        assert_eq!(
+            other.admit().err(),
+            Some(Throttled {
+                scope: QuotaScope::Canister,
+                limit: QuotaLimit::Concurrency,
+                max: 1,
+                retry_after: CONCURRENCY_RETRY_AFTER,
+            })
+        );
+        assert_eq!(other.throttled(), 1);
+
+        drop(permit);
+        assert!(other.admit().is_ok());
+    }
+
+    #[test]
+    fn test_limiter_refunds_the_shared_quota() {
+        let canister = Arc::new(Limiter::new(
+            CallQuota {
+                rate_per_sec: Some(1),
+                burst: Some(2),
+                ..Default::default()
+            },
+            None,
+        ));
+        let program = Arc::new(Limiter::new(
+            CallQuota {
+                rate_per_sec: Some(1),
+                burst: Some(1),
+                ..Default::default()
+            },
+            Some(canister.clone()),
+        ));
+        let other = Arc::new(Limiter::new(CallQuota::default(), Some(canister.clone())));
+
+        assert!(program.admit().is_ok());
+        // turned away by its own quota, the program leaves the canister's tokens to the others
+        for _ in 0..3 {
+            assert_eq!(
+                program
+                    .admit()
+                    .err()
+                    .map(|throttled| (throttled.scope, throttled.limit)),
+                Some((QuotaScope::Program, QuotaLimit::Rate))
+            );
+        }
+        assert!(other.admit().is_ok());
+        assert_eq!(
+            other.admit().err().map(|throttled| throttled.limit),
+            Some(QuotaLimit::Rate)
+        );
+    }
+}
@@ -0,0 +1,431 @@
+//! The HTTP routes of the harness node, these are thin wrappers over the [`NodeServer`] methods.
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    async_trait,
+    body::{to_bytes, Body, Bytes},
+    extract::{DefaultBodyLimit, FromRequestParts, Path, Request, State},
+    http::{header, request::Parts, HeaderValue, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+
+use sha2::{Digest, Sha256};
+
+use harness_primitives::{
+    auth::{SignedRequest, SIGNED_HEADERS},
+    error::Error,
+    http::{Header, PullProgram},
+    program::ProgramId,
+};
+
+use crate::{
+    health::Readiness,
+    inventory::{ProgramInfo, PulledProgram},
+    telemetry, IcpAgent, NodeServer,
+};
+
+impl<T> NodeServer<T>
+where
+    T: IcpAgent + Send + Sync + 'static,
+{
+    /// Creates the router serving every route of the node, for embedders serving the node on a
+    /// single listener. It is the [`NodeServer::public_router`] merged with the
+    /// [`NodeServer::admin_router`], the admin routes still require the admin token when one is
+    /// configured.
+    pub fn router(self: Arc<Self>) -> Router {
+        self.clone().public_router().merge(self.admin_router())
+    }
+
+    /// Creates the router serving the canisters and load balancers, meant to be reachable from
+    /// the internet:
+    ///
+    /// - `GET /healthz` answers `200` as long as the node is running.
+    /// - `GET /readyz` reports the node's [`Readiness`], answering `503` when it cannot accept
+    ///   work.
+    /// - `POST /procedure` calls the `Program-Procedure` of the `Program-Identifier` program with
+    ///   the candid encoded body, within the optional `Program-Timeout` in milliseconds. Unless
+    ///   `require_signed_calls` is unset, the call must carry the `Harness-Timestamp` and
+    ///   `Harness-Signature` of the program's canister and is answered with `401` otherwise.
+    ///   Calls over the quota of the program are answered with `429` and a `Retry-After`.
+    ///
+    /// Request bodies are limited to the configured `max_request_size`, every request is traced
+    /// under the request id taken from or echoed in the `X-Request-Id` header.
+    pub fn public_router(self: Arc<Self>) -> Router {
+        let body_limit = DefaultBodyLimit::max(self.config.max_request_size);
+
+        let router = Router::new()
+            .route("/healthz", get(healthz))
+            .route("/readyz", get(readyz::<T>))
+            .route(
+                "/procedure",
+                post(call_procedure::<T>).layer(middleware::from_fn_with_state(
+                    self.clone(),
+                    authenticate::<T>,
+                )),
+            )
+            .layer(body_limit)
+            .with_state(self);
+
+        telemetry::trace_requests(router)
+    }
+
+    /// Creates the router managing the programs of the node, meant for operators only:
+    ///
+    /// - `GET /metrics` serves the node metrics in the Prometheus text format.
+    /// - `GET /program` lists the loaded programs as [`ProgramInfo`]s.
+    /// - `GET /program/:id` describes the loaded program as a [`ProgramInfo`].
+    /// - `POST /program` pulls a program from its canister and loads it, the body is a [`PullProgram`].
+    /// - `DELETE /program` unloads the program named by the `Program-Identifier` header and
+    ///   deletes its stored copy.
+    ///
+    /// When the admin `token` is configured, requests must carry it as a bearer token in their
+    /// `Authorization` header and are answered with `401` otherwise. [`Config::validate`]
+    /// requires it, an embedder serving the routes without it must keep other users of the
+    /// device from reaching them.
+    ///
+    /// [`Config::validate`]: crate::config::Config::validate
+    pub fn admin_router(self: Arc<Self>) -> Router {
+        let body_limit = DefaultBodyLimit::max(self.config.max_request_size);
+
+        let router = Router::new()
+            .route("/metrics", get(metrics::<T>))
+            .route(
+                "/program",
+                get(list_programs::<T>)
+                    .post(pull_program::<T>)
+                    .delete(remove_program::<T>),
+            )
+            .route("/program/:id", get(get_program::<T>))
+            .route_layer(middleware::from_fn_with_state(
+                self.clone(),
+                authorize_admin::<T>,
+            ))
+            .layer(body_limit)
+            .with_state(self);
+
+        telemetry::trace_requests(router)
+    }
+}
+
+/// Answers the client with the status code and message of the harness error.
+struct ApiError(Error);
+
+impl From<Error> for ApiError {
+    fn from(err: Error) -> Self {
+        Self(err)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status_code =
+            StatusCode::from_u16(self.0.status_code()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+        let mut response = (
+            status_code,
+            [(header::CONTENT_TYPE, "text/plain")],
+            self.0.to_string(),
+        )
+            .into_response();
+        match self.0 {
+            Error::RateLimited { retry_after, .. } => {
+                // whole seconds, rounded up so that the retry is not turned away again
+                let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, HeaderValue::from(secs.max(1)));
+            }
+            // the instances and the signed calls kept free up within moments
+            Error::Busy { .. } => {
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, HeaderValue::from(1));
+            }
+            _ => {}
+        }
+        response
+    }
+}
+
+/// The program the request is for, taken from the `Program-Identifier` header.
+struct ProgramIdHeader(ProgramId);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ProgramIdHeader {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self(header_value(parts, Header::ProgramId)?.parse()?))
+    }
+}
+
+/// The deadline of the call, taken from the optional `Program-Timeout` header in milliseconds.
+struct ProgramTimeoutHeader(Option<Duration>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ProgramTimeoutHeader {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        if !parts
+            .headers
+            .contains_key(Header::ProgramTimeout.to_string())
+        {
+            return Ok(Self(None));
+        }
+
+        let timeout = header_value(parts, Header::ProgramTimeout)?
+            .parse::<u64>()
+            .map_err(|err| {
+                Error::io(
+                    &format!("{} header must be in milliseconds", Header::ProgramTimeout),
+                    Some(err),
+                )
+            })?;
+        Ok(Self(Some(Duration::from_millis(timeout))))
+    }
+}
+
+/// The canister making the call, taken from the optional `Canister-Id` header.
+struct CanisterIdHeader(Option<String>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for CanisterIdHeader {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        if !parts.headers.contains_key(Header::CanisterId.to_string()) {
+            return Ok(Self(None));
+        }
+        Ok(Self(Some(header_value(parts, Header::CanisterId)?)))
+    }
+}
+
+/// The procedure to call into, taken from the `Program-Procedure` header.
+struct ProgramProcHeader(String);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ProgramProcHeader {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self(header_value(parts, Header::ProgramProc)?))
+    }
+}
+
+fn header_value(parts: &Parts, header: Header) -> Result<String, ApiError> {
+    let name = header.to_string();
+    parts
+        .headers
+        .get(&name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_string())
+        .ok_or_else(|| {
+            ApiError(Error::io::<anyhow::Error>(
+                &format!("{name} header could not be retrieved"),
+                None,
+            ))
+        })
+}
+
+/// Checks the bearer token of the admin request, noop when no admin token is configured.
+async fn authorize_admin<T: IcpAgent>(
+    State(server): State<Arc<NodeServer<T>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(token) = &server.config.admin.token else {
+        return next.run(request).await;
+    };
+
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    // the digests are compared so that the time taken does not leak the token
+    let authorized =
+        bearer.is_some_and(|bearer| Sha256::digest(bearer.trim()) == Sha256::digest(token));
+    if !authorized {
+        let mut response = ApiError(Error::Unauthorized {
+            message: "the admin routes require the admin bearer token".to_string(),
+        })
+        .into_response();
+        response
+            .headers_mut()
+            .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        return response;
+    }
+
+    next.run(request).await
+}
+
+/// Verifies the signature of the procedure call before it is served, a call the replicas of the
+/// canister already sent is answered with the response to the first of them.
+async fn authenticate<T: IcpAgent>(
+    State(server): State<Arc<NodeServer<T>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !server.config.require_signed_calls {
+        return next.run(request).await;
+    }
+
+    let (parts, body) = request.into_parts();
+    let Ok(body) = to_bytes(body, server.config.max_request_size).await else {
+        return (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            [(header::CONTENT_TYPE, "text/plain")],
+            "the request body is too large",
+        )
+            .into_response();
+    };
+
+    let signature = match verify_signature(&server, &parts, &body).await {
+        Ok(signature) => signature,
+        Err(err) => return err.into_response(),
+    };
+
+    let request = Request::from_parts(parts, Body::from(body));
+    server
+        .replays
+        .serve(&signature, next.run(request))
+        .await
+        .unwrap_or_else(|err| ApiError(err).into_response())
+}
+
+async fn verify_signature<T: IcpAgent>(
+    server: &NodeServer<T>,
+    parts: &Parts,
+    body: &[u8],
+) -> Result<String, ApiError> {
+    let program_id = header_value(parts, Header::ProgramId)?.parse()?;
+
+    let timestamp = header_value(parts, Header::Timestamp)
+        .ok()
+        .and_then(|timestamp| timestamp.parse::<u64>().ok());
+    let signature = header_value(parts, Header::Signature).ok();
+    let (Some(timestamp), Some(signature)) = (timestamp, signature) else {
+        return Err(ApiError(Error::Unauthorized {
+            message: format!(
+                "the call must carry the {} and {} of its canister",
+                Header::Timestamp,
+                Header::Signature
+            ),
+        }));
+    };
+
+    let canister_id = header_value(parts, Header::CanisterId)?;
+    let headers = SIGNED_HEADERS
+        .into_iter()
+        .map(|header| header_value(parts, header))
+        .collect::<Result<Vec<_>, _>>()?;
+    let request = SignedRequest {
+        method: parts.method.as_str(),
+        path: parts.uri.path(),
+        headers: &headers,
+        body,
+        timestamp,
+    };
+    server
+        .verify_call(&program_id, &canister_id, &request, &signature)
+        .await?;
+    Ok(signature)
+}
+
+async fn healthz() -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+async fn readyz<T: IcpAgent>(
+    State(server): State<Arc<NodeServer<T>>>,
+) -> (StatusCode, Json<Readiness>) {
+    let readiness = server.readiness().await;
+    let status_code = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status_code, Json(readiness))
+}
+
+async fn metrics<T: IcpAgent>(State(server): State<Arc<NodeServer<T>>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+        server.metrics().await,
+    )
+}
+
+async fn list_programs<T: IcpAgent>(
+    State(server): State<Arc<NodeServer<T>>>,
+) -> Json<Vec<ProgramInfo>> {
+    Json(server.programs().await)
+}
+
+async fn get_program<T: IcpAgent>(
+    State(server): State<Arc<NodeServer<T>>>,
+    Path(program_id): Path<String>,
+) -> Result<Json<ProgramInfo>, ApiError> {
+    Ok(Json(server.program(&program_id.parse()?).await?))
+}
+
+async fn pull_program<T: IcpAgent>(
+    State(server): State<Arc<NodeServer<T>>>,
+    Json(program): Json<PullProgram>,
+) -> Result<(StatusCode, Json<PulledProgram>), ApiError> {
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(server.pull_program(program).await?),
+    ))
+}
+
+async fn remove_program<T: IcpAgent>(
+    State(server): State<Arc<NodeServer<T>>>,
+    ProgramIdHeader(program_id): ProgramIdHeader,
+) -> Result<StatusCode, ApiError> {
+    server.remove_program(&program_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn call_procedure<T: IcpAgent>(
+    State(server): State<Arc<NodeServer<T>>>,
+    ProgramIdHeader(program_id): ProgramIdHeader,
+    ProgramProcHeader(procedure): ProgramProcHeader,
+    ProgramTimeoutHeader(timeout): ProgramTimeoutHeader,
+    CanisterIdHeader(canister_id): CanisterIdHeader,
+    payload: Bytes,
+) -> Response {
+    if let Some(canister_id) = canister_id {
+        if let Err(err) = server.load_on_demand(&program_id, &canister_id).await {
+            tracing::warn!(error = %err, "failed to load the program on demand");
+            return ApiError(err).into_response();
+        }
+    }
+
+    match server
+        .call_procedure(&program_id, &procedure, &payload, timeout)
+        .await
+    {
+        Ok(invocation) => (
+            StatusCode::OK,
+            [(
+                header::CONTENT_TYPE.to_string(),
+                "application/octet-stream".to_string(),
+            )],
+            // only the programs held to a resource policy are metered
+            invocation
+                .fuel_consumed
+                .map(|fuel| [(Header::FuelConsumed.to_string(), fuel.to_string())]),
+            invocation.response,
+        )
+            .into_response(),
+        Err(err) => {
+            tracing::warn!(error = %err, "procedure call failed");
+            ApiError(err).into_response()
+        }
+    }
+}
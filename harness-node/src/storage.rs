@@ -0,0 +1,323 @@
+//! Keeps the programs pulled to the node on disk so they are served again after a restart.
+//!
+//! Each program is stored under `<data_dir>/programs/<hex encoded program id>/` as the module it
+//! was pulled as, named after its hash, and a `metadata.json` describing where it came from. A
+//! new version is written next to the stored one, replacing the metadata switches over to it.
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+
+use harness_primitives::{
+    error::{Error, Result},
+    http::PullProgram,
+    internals::Schema,
+    manifest::SignedManifest,
+    program::{CallQuota, ProgramId, ResourcePolicy},
+};
+
+/// The module of the programs stored before modules were named after their hash.
+const LEGACY_MODULE_FILE: &str = "module.wasm";
+const METADATA_FILE: &str = "metadata.json";
+
+/// Describes a stored program.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProgramMetadata {
+    pub program_id: String,
+    pub canister_id: String,
+    /// The URL of the IC replica the program was pulled through.
+    pub url: String,
+    /// The hex encoded SHA-256 hash of the module, verified against the hash published by the
+    /// canister when the program was pulled.
+    pub sha256: String,
+    /// When the program was pulled, in seconds since the unix epoch.
+    pub pulled_at: u64,
+    /// The resource policy the program was pulled with.
+    #[serde(default)]
+    pub policy: Option<ResourcePolicy>,
+    /// The call quota the program was pulled with.
+    #[serde(default)]
+    pub quota: Option<CallQuota>,
+    /// The manifest signed by the program owner, kept to verify the program when it is restored.
+    #[serde(default)]
+    pub manifest: Option<SignedManifest>,
+    /// The trusted publisher that signed the manifest.
+    #[serde(default)]
+    pub publisher: Option<String>,
+    /// The hex encoded secret the calls of the canister are signed with.
+    #[serde(default)]
+    pub device_secret: Option<String>,
+    /// The schema the canister declares for the program.
+    #[serde(default)]
+    pub schema: Option<Schema>,
+}
+
+impl ProgramMetadata {
+    /// Describes the program pulled now from the canister, under the id the canister declares.
+    pub fn new(program: &PullProgram, program_id: &ProgramId, schema: Schema, code: &[u8]) -> Self {
+        Self {
+            program_id: program_id.as_str().to_string(),
+            canister_id: program.canister_id.clone(),
+            url: program.url.clone(),
+            sha256: sha256_hex(code),
+            pulled_at: unix_time(),
+            policy: program.policy.clone(),
+            quota: program.quota.clone(),
+            manifest: None,
+            publisher: None,
+            device_secret: program.device_secret.clone(),
+            schema: Some(schema),
+        }
+    }
+}
+
+/// A program read back from the store.
+pub struct StoredProgram {
+    pub metadata: ProgramMetadata,
+    pub code: Vec<u8>,
+}
+
+/// The on-disk copy of the programs loaded to the node.
+pub struct ProgramStore {
+    dir: PathBuf,
+    /// Whether the store was found writable, until a write to it fails.
+    writable: AtomicBool,
+}
+
+impl ProgramStore {
+    /// The store kept in the `programs` directory of the node's data directory.
+    pub fn new(data_dir: &Path) -> Self {
+        Self {
+            dir: data_dir.join("programs"),
+            writable: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether programs can be written to the store. The store is probed with a write the first
+    /// time and after a write to it failed, not on every check.
+    pub async fn is_writable(&self) -> bool {
+        if self.writable.load(Ordering::Relaxed) {
+            return true;
+        }
+        let writable = crate::health::is_writable(&self.dir).await;
+        self.writable.store(writable, Ordering::Relaxed);
+        writable
+    }
+
+    /// Writes the program to the store, replacing any stored copy of the same program.
+    ///
+    /// The metadata is written last, a program is only restored once its metadata exists and
+    /// the previous version stays in place until then.
+    pub async fn save(&self, metadata: &ProgramMetadata, code: &[u8]) -> Result<()> {
+        let result = self.write(metadata, code).await;
+        self.track(result)
+    }
+
+    async fn write(&self, metadata: &ProgramMetadata, code: &[u8]) -> Result<()> {
+        let dir = self.program_dir(&metadata.program_id);
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .map_err(|err| storage_error("failed to create the program directory", err))?;
+        sync_dir(&self.dir).await?;
+
+        let module = module_file(&metadata.sha256);
+        let metadata = serde_json::to_vec_pretty(metadata)
+            .map_err(|err| storage_error("failed to encode the program metadata", err))?;
+        write_atomic(&dir.join(&module), code).await?;
+        write_atomic(&dir.join(METADATA_FILE), &metadata).await?;
+
+        // the modules of the previous versions are no longer referenced, one left behind is
+        // only taking up space
+        if let Ok(mut entries) = tokio::fs::read_dir(&dir).await {
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let name = entry.file_name();
+                if name.to_string_lossy().starts_with("module") && name != *module {
+                    _ = tokio::fs::remove_file(entry.path()).await;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Rewrites the metadata of a stored program, noop if it is not stored.
+    pub async fn save_metadata(&self, metadata: &ProgramMetadata) -> Result<()> {
+        let dir = self.program_dir(&metadata.program_id);
+        if !tokio::fs::try_exists(dir.join(METADATA_FILE))
+            .await
+            .unwrap_or(false)
+        {
+            return Ok(());
+        }
+
+        let metadata = serde_json::to_vec_pretty(metadata)
+            .map_err(|err| storage_error("failed to encode the program metadata", err))?;
+        let result = write_atomic(&dir.join(METADATA_FILE), &metadata).await;
+        self.track(result)
+    }
+
+    /// Removes the stored copy of the program, noop if it is not stored.
+    pub async fn remove(&self, program_id: &ProgramId) -> Result<()> {
+        let result = match tokio::fs::remove_dir_all(self.program_dir(program_id.as_str())).await {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                Err(storage_error("failed to remove the stored program", err))
+            }
+            _ => Ok(()),
+        };
+        self.track(result)
+    }
+
+    /// Reads every stored program.
+    ///
+    /// Programs that cannot be read or whose module no longer matches its hash are skipped and
+    /// reported alongside the programs that were read.
+    pub async fn load_all(&self) -> Result<(Vec<StoredProgram>, Vec<Error>)> {
+        let mut entries = match tokio::fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Default::default()),
+            Err(err) => return Err(storage_error("failed to read the program store", err)),
+        };
+
+        let (mut programs, mut errors) = (Vec::new(), Vec::new());
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|err| storage_error("failed to read the program store", err))?
+        {
+            match load(&entry.path()).await {
+                Ok(Some(program)) => programs.push(program),
+                Ok(None) => {}
+                Err(err) => errors.push(err),
+            }
+        }
+
+        Ok((programs, errors))
+    }
+
+    /// Has the store probed again on the next check once a write to it failed.
+    fn track(&self, result: Result<()>) -> Result<()> {
+        if result.is_err() {
+            self.writable.store(false, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn program_dir(&self, program_id: &str) -> PathBuf {
+        // program ids are free-form, hex encoding keeps them from escaping the store
+        self.dir.join(hex::encode(program_id.as_bytes()))
+    }
+}
+
+/// Reads the program stored in the directory, `None` if it was never fully written.
+async fn load(dir: &Path) -> Result<Option<StoredProgram>> {
+    let metadata = match tokio::fs::read(dir.join(METADATA_FILE)).await {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(storage_error("failed to read the program metadata", err)),
+    };
+    let metadata: ProgramMetadata = serde_json::from_slice(&metadata).map_err(|err| {
+        storage_error(
+            &format!("invalid program metadata in {}", dir.display()),
+            err,
+        )
+    })?;
+
+    let code = match tokio::fs::read(dir.join(module_file(&metadata.sha256))).await {
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            tokio::fs::read(dir.join(LEGACY_MODULE_FILE)).await
+        }
+        code => code,
+    }
+    .map_err(|err| storage_error("failed to read the program module", err))?;
+    if sha256_hex(&code) != metadata.sha256 {
+        return Err(Error::Internal {
+            message: format!(
+                "the stored module of program '{}' does not match its hash",
+                metadata.program_id
+            ),
+            inner: None,
+        });
+    }
+
+    Ok(Some(StoredProgram { metadata, code }))
+}
+
+/// The name of the module file with the hash, so that each version has a file of its own.
+fn module_file(sha256: &str) -> String {
+    format!("module-{}.wasm", sha256.to_ascii_lowercase())
+}
+
+/// Writes to a temporary file first so that a power loss never leaves a partial file behind,
+/// the directory is synced for the rename to be durable as well.
+///
+/// The file is readable by the node's user only, the metadata holds the device secret.
+async fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options
+        .open(&tmp)
+        .await
+        .map_err(|err| storage_error("failed to write the program", err))?;
+    // the mode only applies to new files, a temporary file left by a power loss keeps its own
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))
+        .await
+        .map_err(|err| storage_error("failed to write the program", err))?;
+    file.write_all(contents)
+        .await
+        .map_err(|err| storage_error("failed to write the program", err))?;
+    file.sync_all()
+        .await
+        .map_err(|err| storage_error("failed to write the program", err))?;
+    tokio::fs::rename(&tmp, path)
+        .await
+        .map_err(|err| storage_error("failed to write the program", err))?;
+    match path.parent() {
+        Some(dir) => sync_dir(dir).await,
+        None => Ok(()),
+    }
+}
+
+/// Flushes the entries of the directory to disk, noop where directories cannot be opened.
+async fn sync_dir(dir: &Path) -> Result<()> {
+    #[cfg(unix)]
+    tokio::fs::File::open(dir)
+        .await
+        .map_err(|err| storage_error("failed to open the program directory", err))?
+        .sync_all()
+        .await
+        .map_err(|err| storage_error("failed to sync the program directory", err))?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+fn storage_error<E>(message: &str, err: E) -> Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    Error::Internal {
+        message: message.to_string(),
+        inner: Some(Box::new(err)),
+    }
+}
+
+/// The current time in seconds since the unix epoch.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// The hex encoded SHA-256 hash of the bytes.
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
@@ -0,0 +1,87 @@
+//! The node logs. Every request is traced in a span carrying its request id, the program and
+//! procedure it is for and its outcome.
+use axum::{
+    http::{HeaderName, Request, Response},
+    Router,
+};
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, RequestId, SetRequestIdLayer},
+    trace::{DefaultOnFailure, TraceLayer},
+};
+use tracing::{field::Empty, Level, Span};
+use tracing_subscriber::EnvFilter;
+
+use harness_primitives::http::Header;
+
+use crate::config::{LogFormat, LogLevel};
+
+/// Installs the global subscriber writing the node logs to stdout.
+pub fn init(level: LogLevel, format: LogFormat) {
+    let subscriber = tracing_subscriber::fmt().with_env_filter(EnvFilter::new(level.as_str()));
+    match format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().flatten_event(true).init(),
+    }
+}
+
+/// Traces the requests served by the router.
+///
+/// The request id is taken from the `X-Request-Id` header, or generated when the client sends
+/// none, and echoed in the response so a client can correlate its call with the node logs.
+pub(crate) fn trace_requests(router: Router) -> Router {
+    let request_id = HeaderName::from_bytes(Header::RequestId.to_string().as_bytes())
+        .expect("the header name is valid; qed");
+
+    router
+        .layer(PropagateRequestIdLayer::new(request_id.clone()))
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(make_span)
+                .on_request(())
+                .on_response(on_response)
+                .on_failure(DefaultOnFailure::new().level(Level::WARN)),
+        )
+        .layer(SetRequestIdLayer::new(request_id, MakeRequestUuid))
+}
+
+fn make_span<B>(request: &Request<B>) -> Span {
+    let header = |header: Header| {
+        request
+            .headers()
+            .get(header.to_string())
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string()
+    };
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .unwrap_or_default();
+
+    tracing::info_span!(
+        "request",
+        request_id,
+        method = %request.method(),
+        path = request.uri().path(),
+        program_id = header(Header::ProgramId),
+        procedure = header(Header::ProgramProc),
+        status = Empty,
+        outcome = Empty,
+    )
+}
+
+fn on_response<B>(response: &Response<B>, latency: std::time::Duration, span: &Span) {
+    let status = response.status();
+    span.record("status", status.as_u16());
+    let outcome = if status.is_success() {
+        "ok"
+    } else if status.is_client_error() {
+        "rejected"
+    } else {
+        "failed"
+    };
+    span.record("outcome", outcome);
+
+    tracing::info!(latency_ms = latency.as_millis() as u64, "served");
+}
@@ -0,0 +1,138 @@
+//! The publishers whose programs the node loads. When publishers are configured, a program is
+//! only loaded along with a manifest describing it, signed by one of them.
+use std::str::FromStr;
+
+use ed25519_consensus::{Signature, VerificationKey};
+use serde::Deserialize;
+
+use harness_primitives::{
+    error::{Error, Result},
+    manifest::{ProgramManifest, SignedManifest},
+};
+
+/// A publisher trusted to sign programs.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct TrustedPublisher {
+    /// The name the publisher is reported by.
+    pub name: String,
+    /// The hex encoded ed25519 public key of the publisher.
+    pub public_key: PublicKey,
+}
+
+/// An ed25519 public key.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct PublicKey([u8; 32]);
+
+impl FromStr for PublicKey {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        hex::decode(s.trim())
+            .ok()
+            .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+            .filter(|bytes| VerificationKey::try_from(*bytes).is_ok())
+            .map(Self)
+            .ok_or_else(|| {
+                Error::io::<anyhow::Error>(
+                    "a publisher key must be a hex encoded ed25519 public key",
+                    None,
+                )
+            })
+    }
+}
+
+impl TryFrom<String> for PublicKey {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<[u8; 32]> for PublicKey {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+/// A manifest verified to be signed by a trusted publisher.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifiedManifest {
+    /// The name of the publisher that signed the manifest.
+    pub publisher: String,
+    pub manifest: ProgramManifest,
+}
+
+/// The publishers trusted by the node.
+#[derive(Debug, Clone, Default)]
+pub struct TrustStore {
+    publishers: Vec<TrustedPublisher>,
+}
+
+impl TrustStore {
+    pub fn new(publishers: &[TrustedPublisher]) -> Self {
+        Self {
+            publishers: publishers.to_vec(),
+        }
+    }
+
+    /// Whether programs are loaded without a signed manifest, no publisher is trusted then.
+    pub fn is_open(&self) -> bool {
+        self.publishers.is_empty()
+    }
+
+    /// Verifies that the manifest is signed by a trusted publisher and describes the program
+    /// with the given id and module hash.
+    pub fn verify(
+        &self,
+        program_id: &str,
+        sha256: &str,
+        signed: Option<&SignedManifest>,
+    ) -> Result<VerifiedManifest> {
+        let Some(signed) = signed else {
+            return Err(integrity_error(
+                "the program is not signed, only programs signed by a trusted publisher are loaded",
+            ));
+        };
+
+        let publisher = self
+            .publishers
+            .iter()
+            .find(|publisher| publisher.public_key.0.as_slice() == signed.public_key)
+            .ok_or_else(|| integrity_error("the program is signed by an untrusted publisher"))?;
+
+        let key = VerificationKey::try_from(publisher.public_key.0)
+            .map_err(|_| integrity_error("the publisher key is not a valid ed25519 key"))?;
+        let signature = Signature::try_from(signed.signature.as_slice())
+            .map_err(|_| integrity_error("the manifest signature is malformed"))?;
+        key.verify(&signature, &signed.manifest)
+            .map_err(|_| integrity_error("the manifest signature is invalid"))?;
+
+        let manifest = signed.manifest()?;
+        if manifest.program_id != program_id {
+            return Err(integrity_error(&format!(
+                "the manifest describes the program '{}', not '{program_id}'",
+                manifest.program_id
+            )));
+        }
+        if !manifest.sha256.eq_ignore_ascii_case(sha256) {
+            return Err(integrity_error(&format!(
+                "the manifest describes the module {}, not {sha256}",
+                manifest.sha256
+            )));
+        }
+
+        Ok(VerifiedManifest {
+            publisher: publisher.name.clone(),
+            manifest,
+        })
+    }
+}
+
+fn integrity_error(message: &str) -> Error {
+    Error::Integrity {
+        message: message.to_string(),
+    }
+}
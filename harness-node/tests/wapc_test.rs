@@ -1,26 +1,118 @@
-use std::io::prelude::*;
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
+use axum::{
+    body::{to_bytes, Body},
+    http::{HeaderName, Request, StatusCode},
+    Router,
+};
 use candid::{Decode, Encode};
-use ic_agent::AgentError;
+use tower::ServiceExt;
 
-use harness_node::{new_node_server, IcpAgent};
+use ed25519_consensus::SigningKey;
+use harness_node::{
+    config::{CanisterConfig, Config, ProgramLimits, QuotaLimits},
+    new_node_server_with_config,
+    storage::sha256_hex,
+    trust::{PublicKey, TrustedPublisher},
+    IcpAgent, IcpAgentImpl, NodeServer,
+};
 use harness_primitives::{
-    http::{Header, HeaderField, PullProgram, Request},
-    program::ProgramId,
+    auth::{encode_secret, SignedRequest, SECRET_SIZE},
+    error::{Error, Result as HarnessResult},
+    harness_os::ProgramConfig,
+    http::{Header, PullProgram, PROTOCOL_VERSION},
+    internals::{Schema, Service},
+    manifest::{ProgramManifest, SignedManifest},
+    program::{CallQuota, ProgramId, ResourcePolicy},
     HarnessOs,
 };
 
 const HELLO_BIN: &[u8] = include_bytes!("../../assets/sample_harness_code.wasm");
 
-pub struct IcpAgentMock;
+/// The canister the mocked programs are pulled from.
+const CANISTER_ID: &str = "hello";
+
+/// The secret the mocked canister issued to the device.
+const DEVICE_SECRET: [u8; SECRET_SIZE] = [7; SECRET_SIZE];
+
+/// Serves the hello program, or the code set in its place, along with its hash unless another
+/// hash is set and the manifest set for it, and records the devices registered with and removed
+/// from canisters.
+#[derive(Default)]
+pub struct IcpAgentMock {
+    code: Arc<Mutex<Option<Vec<u8>>>>,
+    hash: Arc<Mutex<Option<String>>>,
+    manifest: Arc<Mutex<Option<SignedManifest>>>,
+    registered_devices: Arc<Mutex<Vec<(String, String)>>>,
+    removed_devices: Arc<Mutex<Vec<(String, String)>>>,
+}
 
 impl IcpAgent for IcpAgentMock {
-    async fn get_program_code(
+    async fn get_program_code(&self, _: &str, _: &str) -> HarnessResult<Vec<u8>> {
+        let code = self.code.lock().unwrap().clone();
+        Ok(code.unwrap_or_else(|| HELLO_BIN.to_vec()))
+    }
+
+    async fn get_program_hash(&self, _: &str, _: &str) -> HarnessResult<String> {
+        if let Some(hash) = self.hash.lock().unwrap().clone() {
+            return Ok(hash);
+        }
+        let code = self.code.lock().unwrap().clone();
+        Ok(sha256_hex(code.as_deref().unwrap_or(HELLO_BIN)))
+    }
+
+    async fn get_program_manifest(
+        &self,
+        _: &str,
+        _: &str,
+    ) -> HarnessResult<Option<SignedManifest>> {
+        Ok(self.manifest.lock().unwrap().clone())
+    }
+
+    async fn get_schema(&self, _: &str, _: &str) -> HarnessResult<Schema> {
+        Ok(Schema {
+            version: "0.1.0".to_string(),
+            program: "hello".to_string(),
+            services: vec![Service {
+                name: "hello".to_string(),
+                args: vec!["String".to_string()],
+                rets: "String".to_string(),
+            }],
+        })
+    }
+
+    async fn get_program_id(&self, _: &str, _: &str) -> HarnessResult<ProgramId> {
+        "hello".parse()
+    }
+
+    async fn register_device(
         &self,
+        canister_id: &str,
         _: &str,
+        device_url: &str,
+    ) -> HarnessResult<String> {
+        self.registered_devices
+            .lock()
+            .unwrap()
+            .push((canister_id.to_string(), device_url.to_string()));
+        Ok(encode_secret(&DEVICE_SECRET))
+    }
+
+    async fn remove_device(
+        &self,
+        canister_id: &str,
         _: &str,
-    ) -> core::result::Result<Vec<u8>, AgentError> {
-        Ok(HELLO_BIN.to_vec())
+        device_url: &str,
+    ) -> HarnessResult<()> {
+        self.removed_devices
+            .lock()
+            .unwrap()
+            .push((canister_id.to_string(), device_url.to_string()));
+        Ok(())
     }
 }
 
@@ -42,58 +134,1432 @@ async fn test_hello() {
     assert_eq!(Decode!(&result, String).unwrap(), "Hello, World!");
 }
 
+#[tokio::test(flavor = "multi_thread")]
+async fn test_program_pool() {
+    let program_id = "hello".parse::<ProgramId>().unwrap();
+    let mut harness_os = HarnessOs::default();
+
+    // an empty pool would never serve a call
+    let config = ProgramConfig {
+        pool_size: 0,
+        checkout_timeout: None,
+        ..Default::default()
+    };
+    assert!(harness_os
+        .add_program_with_config(program_id.clone(), HELLO_BIN, &config)
+        .await
+        .is_err());
+
+    let config = ProgramConfig {
+        pool_size: 2,
+        checkout_timeout: None,
+        ..Default::default()
+    };
+    harness_os
+        .add_program_with_config(program_id.clone(), HELLO_BIN, &config)
+        .await
+        .unwrap();
+
+    let pool = harness_os.program(&program_id).unwrap();
+    assert_eq!(pool.size(), 2);
+
+    // more calls than instances, the extra calls wait for an idle instance
+    let calls = (0..8)
+        .map(|n| {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                let result = pool
+                    .call("hello", &Encode!(&format!("World {n}")).unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    Decode!(&result, String).unwrap(),
+                    format!("Hello, World {n}!")
+                );
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for call in calls {
+        call.await.unwrap();
+    }
+
+    assert_eq!(pool.in_use(), 0);
+}
+
+/// A waPC guest whose every call loops forever.
+const LOOP_WAT: &str = r#"
+    (module
+        (memory (export "memory") 1)
+        (func (export "__guest_call") (param i32 i32) (result i32)
+            (loop $forever (br $forever))
+            (i32.const 1)))
+"#;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_call_timeout() {
+    let program_id = "loop".parse::<ProgramId>().unwrap();
+    let mut harness_os = HarnessOs::default();
+    let config = ProgramConfig {
+        pool_size: 1,
+        call_timeout: Some(Duration::from_millis(100)),
+        ..Default::default()
+    };
+    harness_os
+        .add_program_with_config(program_id.clone(), LOOP_WAT.as_bytes(), &config)
+        .await
+        .unwrap();
+    let pool = harness_os.program(&program_id).unwrap();
+
+    // the interrupted instance is dropped, the next call runs on a replacement
+    for _ in 0..2 {
+        let started = Instant::now();
+        let err = pool.call("loop", &[]).await.unwrap_err();
+        assert!(matches!(err, Error::Timeout { .. }), "{err}");
+        assert_eq!(err.status_code(), 504);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(pool.in_use(), 0);
+    }
+
+    // a shorter deadline for the call takes precedence over the program's, on a pooled instance
+    let started = Instant::now();
+    let err = pool
+        .call_with_timeout("loop", &[], Some(Duration::from_millis(20)))
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(&err, Error::Timeout { message } if message.contains("within 20ms")),
+        "{err}"
+    );
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(pool.in_use(), 0);
+
+    // while a longer deadline for the call is still bounded by the program's
+    let err = pool
+        .call_with_timeout("loop", &[], Some(Duration::from_secs(60)))
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(&err, Error::Timeout { message } if message.contains("within 100ms")),
+        "{err}"
+    );
+}
+
+/// A waPC guest whose every call grows its memory by 16 pages.
+const GROW_WAT: &str = r#"
+    (module
+        (memory (export "memory") 1)
+        (func (export "__guest_call") (param i32 i32) (result i32)
+            (drop (memory.grow (i32.const 16)))
+            (i32.const 1)))
+"#;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_resource_policy() {
+    let mut harness_os = HarnessOs::default();
+    let config = |policy| ProgramConfig {
+        pool_size: 1,
+        call_timeout: None,
+        policy,
+        ..Default::default()
+    };
+
+    // the memory cannot grow past 2 pages
+    let program_id = "grow".parse::<ProgramId>().unwrap();
+    let policy = ResourcePolicy {
+        max_memory_bytes: Some(2 * 65536),
+        ..Default::default()
+    };
+    harness_os
+        .add_program_with_config(program_id.clone(), GROW_WAT.as_bytes(), &config(policy))
+        .await
+        .unwrap();
+    let pool = harness_os.program(&program_id).unwrap();
+    for _ in 0..2 {
+        let err = pool.call("grow", &[]).await.unwrap_err();
+        assert!(matches!(err, Error::ResourceExhausted { .. }), "{err}");
+        assert_eq!(err.status_code(), 422);
+    }
+
+    // a module that starts with more memory than allowed is not loaded
+    let policy = ResourcePolicy {
+        max_memory_bytes: Some(32768),
+        ..Default::default()
+    };
+    let err = harness_os
+        .add_program_with_config(program_id, GROW_WAT.as_bytes(), &config(policy))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::ResourceExhausted { .. }), "{err}");
+
+    // a call spinning the CPU runs out of fuel without a deadline
+    let program_id = "loop".parse::<ProgramId>().unwrap();
+    let policy = ResourcePolicy {
+        fuel_per_call: Some(100_000),
+        ..Default::default()
+    };
+    harness_os
+        .add_program_with_config(program_id.clone(), LOOP_WAT.as_bytes(), &config(policy))
+        .await
+        .unwrap();
+    let err = harness_os
+        .call_operation(&program_id, "loop", &[])
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::ResourceExhausted { .. }), "{err}");
+
+    // calls within the budget report the fuel they consumed
+    let program_id = "hello".parse::<ProgramId>().unwrap();
+    let policy = ResourcePolicy {
+        fuel_per_call: Some(100_000_000),
+        ..Default::default()
+    };
+    harness_os
+        .add_program_with_config(program_id.clone(), HELLO_BIN, &config(policy))
+        .await
+        .unwrap();
+    let invocation = harness_os
+        .program(&program_id)
+        .unwrap()
+        .invoke("hello", &Encode!(&String::from("World")).unwrap(), None)
+        .await
+        .unwrap();
+    assert_eq!(
+        Decode!(&invocation.response, String).unwrap(),
+        "Hello, World!"
+    );
+    let fuel_consumed = invocation.fuel_consumed.unwrap();
+    assert!(fuel_consumed > 0 && fuel_consumed < 100_000_000);
+
+    // the programs without a policy are not metered
+    let program_id = "unmetered".parse::<ProgramId>().unwrap();
+    harness_os
+        .add_program_with_config(program_id.clone(), HELLO_BIN, &config(Default::default()))
+        .await
+        .unwrap();
+    let invocation = harness_os
+        .program(&program_id)
+        .unwrap()
+        .invoke("hello", &Encode!(&String::from("World")).unwrap(), None)
+        .await
+        .unwrap();
+    assert_eq!(invocation.fuel_consumed, None);
+}
+
+/// The config of a node keeping its programs in the data directory, loading a single instance
+/// of each program so that pulls stay cheap.
+fn test_config(data_dir: &Path) -> Config {
+    Config {
+        data_dir: data_dir.to_path_buf(),
+        limits: ProgramLimits {
+            pool_size: Some(1),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+/// Creates a node that keeps its programs in the data directory.
+fn node_server(data_dir: &Path) -> Arc<NodeServer<IcpAgentMock>> {
+    Arc::new(new_node_server_with_config(
+        IcpAgentMock::default(),
+        test_config(data_dir),
+    ))
+}
+
+/// Registers the hello program to the device through the router.
+async fn pull_hello(router: &Router) {
+    let (status, _) = pull(router, "hello").await;
+
+    // response should be created status
+    assert_eq!(status, StatusCode::ACCEPTED);
+}
+
+/// Pulls the program from the mocked canister, returning the status and body of the response.
+async fn pull(router: &Router, program_id: &str) -> (StatusCode, serde_json::Value) {
+    let payload = serde_json::to_string(&PullProgram {
+        canister_id: "hello".to_string(),
+        program_id: Some(program_id.to_string()),
+        url: "http://localhost:8000".to_string(),
+        policy: None,
+        quota: None,
+        device_secret: Some(encode_secret(&DEVICE_SECRET)),
+    })
+    .unwrap();
+    let resp = router
+        .clone()
+        .oneshot(
+            Request::post("/program")
+                .header("Content-Type", "application/json")
+                .body(Body::from(payload))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let status = resp.status();
+    let buf = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    (status, serde_json::from_slice(&buf).unwrap_or_default())
+}
+
+/// Calls the procedure as the canister of the program would, signing the call now.
+fn procedure_request(program_id: &str, procedure: &str, payload: Vec<u8>) -> Request<Body> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    signed_request(program_id, procedure, payload, now.as_nanos() as u64)
+}
+
+/// Calls the procedure with the signature of the canister the device holds the secret of.
+fn signed_request(
+    program_id: &str,
+    procedure: &str,
+    payload: Vec<u8>,
+    timestamp: u64,
+) -> Request<Body> {
+    let headers = [
+        program_id.to_string(),
+        procedure.to_string(),
+        CANISTER_ID.to_string(),
+    ];
+    let signature = SignedRequest {
+        method: "POST",
+        path: "/procedure",
+        headers: &headers,
+        body: &payload,
+        timestamp,
+    }
+    .sign(&DEVICE_SECRET);
+
+    Request::post("/procedure")
+        .header(Header::ProgramId.to_string(), program_id)
+        .header(Header::ProgramProc.to_string(), procedure)
+        .header(Header::CanisterId.to_string(), CANISTER_ID)
+        .header(Header::Timestamp.to_string(), timestamp.to_string())
+        .header(Header::Signature.to_string(), signature)
+        .body(Body::from(payload))
+        .unwrap()
+}
+
 #[tokio::test]
 async fn test_with_node_impl() {
-    let mut node_server = new_node_server(IcpAgentMock);
+    let data_dir = tempfile::tempdir().unwrap();
+    let mut config = test_config(data_dir.path());
+    config.limits.fuel_per_call = Some(1_000_000_000);
+    let router = Arc::new(new_node_server_with_config(IcpAgentMock::default(), config)).router();
 
     // program registration to the device
+    pull_hello(&router).await;
+
+    // procedure invocation for the loaded program
     {
-        let payload = serde_json::to_string(&PullProgram {
-            canister_id: "hello".to_string(),
-            program_id: "hello".to_string(),
+        let resp = router
+            .clone()
+            .oneshot(procedure_request(
+                "hello",
+                "hello",
+                Encode!(&String::from("World")).unwrap(),
+            ))
+            .await
+            .unwrap();
+
+        // status ok
+        assert_eq!(resp.status(), StatusCode::OK);
+        let fuel_consumed = resp.headers()[Header::FuelConsumed.to_string()]
+            .to_str()
+            .unwrap()
+            .parse::<u64>()
+            .unwrap();
+        assert!(fuel_consumed > 0);
+
+        let buf = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+
+        // response should be "Hello, World!"
+        assert_eq!(Decode!(&buf, String).unwrap(), "Hello, World!");
+    }
+
+    // the program is unloaded from the device
+    {
+        let resp = router
+            .clone()
+            .oneshot(
+                Request::delete("/program")
+                    .header(Header::ProgramId.to_string(), "hello")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        // the device no longer holds the secret to verify calls into the program
+        let resp = router
+            .clone()
+            .oneshot(procedure_request(
+                "hello",
+                "hello",
+                Encode!(&String::from("World")).unwrap(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+}
+
+#[tokio::test]
+async fn test_health_and_readiness() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let router = node_server(data_dir.path()).router();
+    pull_hello(&router).await;
+
+    let resp = router
+        .clone()
+        .oneshot(Request::get("/healthz").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = router
+        .clone()
+        .oneshot(Request::get("/readyz").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let buf = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let readiness: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+    assert_eq!(readiness["ready"], true);
+    assert_eq!(readiness["protocol_version"], PROTOCOL_VERSION);
+    assert_eq!(readiness["programs"], 1);
+    assert_eq!(readiness["pool"]["in_use"], 0);
+    assert_eq!(readiness["data_dir_writable"], true);
+
+    // the store is not probed again until writing to it fails
+    let programs = data_dir.path().join("programs");
+    std::fs::remove_dir_all(&programs).unwrap();
+    std::fs::write(&programs, b"").unwrap();
+    let resp = router
+        .clone()
+        .oneshot(Request::get("/readyz").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = router
+        .clone()
+        .oneshot(
+            Request::delete("/program")
+                .header(Header::ProgramId.to_string(), "hello")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let resp = router
+        .oneshot(Request::get("/readyz").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    // a data directory that cannot be created leaves the node unable to store programs
+    let file = data_dir.path().join("file");
+    std::fs::write(&file, b"").unwrap();
+    let resp = node_server(&file.join("data"))
+        .router()
+        .oneshot(Request::get("/readyz").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn test_startup_pulls() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        canisters: vec![CanisterConfig {
+            canister_id: CANISTER_ID.to_string(),
             url: "http://localhost:8000".to_string(),
-        })
+            program_id: Some("hello".to_string()),
+            device_secret: Some(encode_secret(&[1; SECRET_SIZE])),
+            limits: Default::default(),
+            load_on_demand: false,
+            network: None,
+            root_key: None,
+        }],
+        ..test_config(data_dir.path())
+    };
+    let server = Arc::new(new_node_server_with_config(IcpAgentMock::default(), config));
+
+    // the node is not ready until the configured programs are pulled
+    let pulls = server.clone().spawn_startup_pulls();
+    let readiness = server.readiness().await;
+    assert!(!readiness.ready);
+    assert_eq!(readiness.pending_pulls, 1);
+
+    pulls.await.unwrap();
+    let readiness = server.readiness().await;
+    assert!(readiness.ready);
+    assert_eq!(readiness.pending_pulls, 0);
+    assert_eq!(readiness.programs, 1);
+}
+
+#[tokio::test]
+async fn test_request_id() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let router = node_server(data_dir.path()).router();
+    let request_id = Header::RequestId.to_string();
+
+    // the request id sent by the client is echoed back
+    let resp = router
+        .clone()
+        .oneshot(
+            Request::get("/healthz")
+                .header(&request_id, "canister-call-42")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
         .unwrap();
+    assert_eq!(resp.headers()[&request_id], "canister-call-42");
 
-        let resp = node_server
-            .handler(Request {
-                method: "POST".to_string(),
-                path: "/program".to_string(),
-                headers: vec![],
-                data: payload.as_bytes().to_vec(),
-            })
+    // one is generated otherwise
+    let resp = router
+        .oneshot(Request::get("/healthz").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert!(!resp.headers()[&request_id].is_empty());
+}
+
+#[tokio::test]
+async fn test_metrics() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let router = node_server(data_dir.path()).router();
+    pull_hello(&router).await;
+
+    for procedure in ["hello", "missing", "also-missing"] {
+        _ = router
+            .clone()
+            .oneshot(procedure_request(
+                "hello",
+                procedure,
+                Encode!(&String::from("World")).unwrap(),
+            ))
             .await
             .unwrap();
+    }
 
-        // response should be created status
-        assert_eq!(resp.status_code, 202);
+    let resp = router
+        .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let buf = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let metrics = String::from_utf8(buf.to_vec()).unwrap();
+
+    for line in [
+        r#"harness_invocations_total{operation="hello",program="hello"} 1"#,
+        // the procedures the schema does not declare share a single series
+        r#"harness_invocations_total{operation="unknown",program="hello"} 2"#,
+        r#"harness_call_duration_seconds_count{operation="hello",program="hello"} 1"#,
+        r#"harness_program_load_duration_seconds_count{program="hello"} 1"#,
+        r#"harness_pool_instances_in_use{program="hello"} 0"#,
+    ] {
+        assert!(metrics.contains(line), "{line} missing from:\n{metrics}");
     }
+    assert!(metrics.contains(r#"harness_failures_total{kind="#));
+    assert!(!metrics.contains("missing"), "{metrics}");
+}
 
-    // procedure invocation for the loaded program
-    {
-        let resp = node_server
-            .handler(Request {
-                method: "POST".to_string(),
-                path: "/procedure".to_string(),
-                headers: vec![
-                    HeaderField(Header::ProgramId.to_string(), "hello".to_string()),
-                    HeaderField(Header::ProgramProc.to_string(), "hello".to_string()),
-                ],
-                data: Encode!(&String::from("World")).unwrap(),
+#[tokio::test]
+async fn test_drain_and_shutdown() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        device_url: Some("https://device.example.com".to_string()),
+        deregister_on_shutdown: true,
+        ..test_config(data_dir.path())
+    };
+    let agent = IcpAgentMock::default();
+    let removed_devices = agent.removed_devices.clone();
+    let server = Arc::new(new_node_server_with_config(agent, config));
+    let router = server.clone().router();
+    pull_hello(&router).await;
+
+    // a draining node still serves calls but tells load balancers to move on
+    server.drain();
+    let resp = router
+        .clone()
+        .oneshot(Request::get("/readyz").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    let resp = router
+        .oneshot(procedure_request(
+            "hello",
+            "hello",
+            Encode!(&String::from("World")).unwrap(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // the device is removed from the canister of the loaded program
+    server.shutdown().await;
+    assert_eq!(
+        *removed_devices.lock().unwrap(),
+        vec![(
+            "hello".to_string(),
+            "https://device.example.com".to_string()
+        )]
+    );
+}
+
+#[tokio::test]
+async fn test_device_registration() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let device = (
+        "hello".to_string(),
+        "https://device.example.com".to_string(),
+    );
+    let config = Config {
+        device_url: Some(device.1.clone()),
+        register_on_startup: true,
+        canisters: vec![CanisterConfig {
+            canister_id: "hello".to_string(),
+            url: "http://localhost:8000".to_string(),
+            program_id: Some("hello".to_string()),
+            device_secret: None,
+            limits: Default::default(),
+            load_on_demand: false,
+            network: None,
+            root_key: None,
+        }],
+        ..test_config(data_dir.path())
+    };
+    let agent = IcpAgentMock::default();
+    let registered_devices = agent.registered_devices.clone();
+    let removed_devices = agent.removed_devices.clone();
+    let server = Arc::new(new_node_server_with_config(agent, config));
+
+    // a program pulled with a secret issued before the device registered again
+    let mut program = PullProgram::from(&server.config().canisters[0]);
+    program.device_secret = Some(encode_secret(&[9; SECRET_SIZE]));
+    server.pull_program(program).await.unwrap();
+
+    // the secret issued on registration verifies the calls from then on
+    server.register_device().await;
+    assert_eq!(*registered_devices.lock().unwrap(), vec![device.clone()]);
+    let resp = server
+        .clone()
+        .router()
+        .oneshot(procedure_request(
+            "hello",
+            "hello",
+            Encode!(&String::from("World")).unwrap(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // and programs are pulled with it
+    let program = PullProgram::from(&server.config().canisters[0]);
+    assert!(program.device_secret.is_none());
+    server.pull_program(program).await.unwrap();
+
+    // the registration is withdrawn on shutdown
+    server.shutdown().await;
+    assert_eq!(*removed_devices.lock().unwrap(), vec![device]);
+}
+
+#[tokio::test]
+async fn test_program_inventory() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let router = node_server(data_dir.path()).router();
+    pull_hello(&router).await;
+
+    let resp = router
+        .clone()
+        .oneshot(procedure_request(
+            "hello",
+            "hello",
+            Encode!(&String::from("World")).unwrap(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = router
+        .clone()
+        .oneshot(Request::get("/program").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let buf = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let programs: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+    assert_eq!(programs.as_array().unwrap().len(), 1);
+
+    let resp = router
+        .clone()
+        .oneshot(Request::get("/program/hello").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let buf = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let program: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+    assert_eq!(program, programs[0]);
+    assert_eq!(program["canister_id"], "hello");
+    assert_eq!(program["size"], HELLO_BIN.len());
+    assert_eq!(program["invocations"], 1);
+    assert_eq!(program["failures"], 0);
+    assert!(program["last_error"].is_null());
+
+    let resp = router
+        .oneshot(
+            Request::get("/program/missing")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_program_upgrade() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let config = test_config(data_dir.path());
+    let agent = IcpAgentMock::default();
+    let code = agent.code.clone();
+    let router = Arc::new(new_node_server_with_config(agent, config)).router();
+
+    let (status, pulled) = pull(&router, "hello").await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert!(pulled["previous_sha256"].is_null());
+    assert_eq!(pulled["sha256"], sha256_hex(HELLO_BIN));
+
+    // a new build of the program, the same module with a custom section appended
+    let mut upgrade = HELLO_BIN.to_vec();
+    upgrade.extend_from_slice(b"\x00\x08\x07upgrade");
+    *code.lock().unwrap() = Some(upgrade.clone());
+
+    // calls keep being served while the new version is loaded
+    let calls = (0..4)
+        .map(|_| {
+            let router = router.clone();
+            tokio::spawn(async move {
+                for _ in 0..4 {
+                    let resp = router
+                        .clone()
+                        .oneshot(procedure_request(
+                            "hello",
+                            "hello",
+                            Encode!(&String::from("World")).unwrap(),
+                        ))
+                        .await
+                        .unwrap();
+                    assert_eq!(resp.status(), StatusCode::OK);
+                }
             })
+        })
+        .collect::<Vec<_>>();
+    let (status, pulled) = pull(&router, "hello").await;
+    for call in calls {
+        call.await.unwrap();
+    }
+
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert_eq!(pulled["previous_sha256"], sha256_hex(HELLO_BIN));
+    assert_eq!(pulled["sha256"], sha256_hex(&upgrade));
+
+    // a build that fails to compile leaves the loaded version serving
+    *code.lock().unwrap() = Some(b"not a wasm module".to_vec());
+    let (status, _) = pull(&router, "hello").await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+
+    let resp = router
+        .clone()
+        .oneshot(Request::get("/program/hello").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let buf = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let program: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+    assert_eq!(program["sha256"], sha256_hex(&upgrade));
+}
+
+#[tokio::test]
+async fn test_program_integrity() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let config = test_config(data_dir.path());
+    let agent = IcpAgentMock::default();
+    let hash = agent.hash.clone();
+    let router = Arc::new(new_node_server_with_config(agent, config)).router();
+
+    // the canister publishes the hash of another build
+    *hash.lock().unwrap() = Some(sha256_hex(b"another build"));
+    let (status, _) = pull(&router, "hello").await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+
+    let resp = router
+        .clone()
+        .oneshot(Request::get("/program/hello").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    // the verified hash is recorded with the program
+    *hash.lock().unwrap() = None;
+    let (status, pulled) = pull(&router, "hello").await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert_eq!(pulled["sha256"], sha256_hex(HELLO_BIN));
+}
+
+/// Signs a manifest of the hello program describing the module with the given hash.
+fn sign_manifest(key: &SigningKey, program_id: &str, sha256: &str) -> SignedManifest {
+    let manifest = serde_json::to_vec(&ProgramManifest {
+        program_id: program_id.to_string(),
+        version: "1.0.0".to_string(),
+        sha256: sha256.to_string(),
+    })
+    .unwrap();
+
+    SignedManifest {
+        signature: key.sign(&manifest).to_bytes().to_vec(),
+        public_key: key.verification_key().to_bytes().to_vec(),
+        manifest,
+    }
+}
+
+#[tokio::test]
+async fn test_signed_programs() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let publisher = SigningKey::from([7; 32]);
+    let config = Config {
+        trusted_publishers: vec![TrustedPublisher {
+            name: "acme".to_string(),
+            public_key: PublicKey::from(publisher.verification_key().to_bytes()),
+        }],
+        ..test_config(data_dir.path())
+    };
+    let agent = IcpAgentMock::default();
+    let manifest = agent.manifest.clone();
+    let router = Arc::new(new_node_server_with_config(agent, config.clone())).router();
+    let hash = sha256_hex(HELLO_BIN);
+
+    // unsigned programs are rejected
+    let (status, _) = pull(&router, "hello").await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+
+    // so are programs signed by an untrusted publisher
+    let stranger = SigningKey::from([8; 32]);
+    *manifest.lock().unwrap() = Some(sign_manifest(&stranger, "hello", &hash));
+    let (status, _) = pull(&router, "hello").await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+
+    // and manifests describing another build, or tampered with
+    *manifest.lock().unwrap() = Some(sign_manifest(&publisher, "hello", &sha256_hex(b"other")));
+    let (status, _) = pull(&router, "hello").await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+
+    let mut tampered = sign_manifest(&publisher, "hello", &hash);
+    tampered.manifest = String::from_utf8(tampered.manifest)
+        .unwrap()
+        .replace("1.0.0", "6.6.6")
+        .into_bytes();
+    *manifest.lock().unwrap() = Some(tampered);
+    let (status, _) = pull(&router, "hello").await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+
+    *manifest.lock().unwrap() = Some(sign_manifest(&publisher, "hello", &hash));
+    let (status, _) = pull(&router, "hello").await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+
+    let resp = router
+        .clone()
+        .oneshot(Request::get("/program/hello").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let buf = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let program: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+    assert_eq!(program["publisher"], "acme");
+    assert_eq!(program["version"], "1.0.0");
+
+    // the program is verified again when it is restored
+    let server = new_node_server_with_config(IcpAgentMock::default(), config.clone());
+    assert_eq!(server.restore_programs().await.unwrap().len(), 1);
+
+    let config = Config {
+        trusted_publishers: vec![TrustedPublisher {
+            name: "stranger".to_string(),
+            public_key: PublicKey::from(stranger.verification_key().to_bytes()),
+        }],
+        ..config
+    };
+    let server = new_node_server_with_config(IcpAgentMock::default(), config);
+    assert!(server.restore_programs().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_programs_restored_after_restart() {
+    let data_dir = tempfile::tempdir().unwrap();
+    pull_hello(&node_server(data_dir.path()).router()).await;
+
+    // the metadata holds the device secret, only the node's user can read it
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let metadata = data_dir
+            .path()
+            .join("programs")
+            .join(hex::encode("hello"))
+            .join("metadata.json");
+        let mode = std::fs::metadata(metadata).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    // an upgrade cut short before its metadata was written leaves the stored version in place
+    let program_dir = data_dir.path().join("programs").join(hex::encode("hello"));
+    std::fs::write(
+        program_dir.join(format!("module-{}.wasm", sha256_hex(b"upgrade"))),
+        b"upgrade",
+    )
+    .unwrap();
+    std::fs::write(program_dir.join("metadata.tmp"), b"{").unwrap();
+
+    // a new node over the same data directory serves the stored program
+    let server = node_server(data_dir.path());
+    let restored = server.restore_programs().await.unwrap();
+    assert_eq!(restored, vec!["hello".parse::<ProgramId>().unwrap()]);
+
+    let result = server
+        .call_procedure(
+            &restored[0],
+            "hello",
+            &Encode!(&String::from("World")).unwrap(),
+            None,
+        )
+        .await
+        .unwrap();
+    assert_eq!(Decode!(&result.response, String).unwrap(), "Hello, World!");
+
+    // removing the program deletes its stored copy
+    server.remove_program(&restored[0]).await.unwrap();
+    let server = node_server(data_dir.path());
+    assert!(server.restore_programs().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_signed_calls() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let router = node_server(data_dir.path()).router();
+    let payload = Encode!(&String::from("World")).unwrap();
+
+    // the device must hold the secret the canister issued to verify the calls of its program
+    let payload_without_secret = serde_json::to_string(&PullProgram {
+        canister_id: "hello".to_string(),
+        program_id: Some("hello".to_string()),
+        url: "http://localhost:8000".to_string(),
+        policy: None,
+        quota: None,
+        device_secret: None,
+    })
+    .unwrap();
+    let resp = router
+        .clone()
+        .oneshot(
+            Request::post("/program")
+                .header("Content-Type", "application/json")
+                .body(Body::from(payload_without_secret))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    pull_hello(&router).await;
+
+    let call = |request: Request<Body>| {
+        let router = router.clone();
+        async move {
+            let resp = router.oneshot(request).await.unwrap();
+            let status = resp.status();
+            let buf = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+            (status, buf)
+        }
+    };
+
+    // unsigned calls are refused
+    let unsigned = Request::post("/procedure")
+        .header(Header::ProgramId.to_string(), "hello")
+        .header(Header::ProgramProc.to_string(), "hello")
+        .body(Body::from(payload.clone()))
+        .unwrap();
+    assert_eq!(call(unsigned).await.0, StatusCode::UNAUTHORIZED);
+
+    // so are calls whose signature does not cover the request
+    let mut tampered = procedure_request("hello", "hello", payload.clone());
+    *tampered.body_mut() = Body::from(Encode!(&String::from("Mallory")).unwrap());
+    assert_eq!(call(tampered).await.0, StatusCode::UNAUTHORIZED);
+
+    // and calls signed outside of the signature window
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let stale = now - Duration::from_secs(600);
+    let replayed = signed_request("hello", "hello", payload.clone(), stale.as_nanos() as u64);
+    assert_eq!(call(replayed).await.0, StatusCode::UNAUTHORIZED);
+
+    // the replicas of the canister send the same call, it is served once
+    let timestamp = now.as_nanos() as u64;
+    for _ in 0..3 {
+        let request = signed_request("hello", "hello", payload.clone(), timestamp);
+        let (status, buf) = call(request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(Decode!(&buf, String).unwrap(), "Hello, World!");
+    }
+
+    // a signature spelled in uppercase is not a new call
+    let mut request = signed_request("hello", "hello", payload.clone(), timestamp);
+    let signature = request.headers()[Header::Signature.to_string()]
+        .to_str()
+        .unwrap()
+        .to_uppercase();
+    request.headers_mut().insert(
+        Header::Signature.to_string().parse::<HeaderName>().unwrap(),
+        signature.parse().unwrap(),
+    );
+    assert_eq!(call(request).await.0, StatusCode::UNAUTHORIZED);
+
+    let resp = router
+        .clone()
+        .oneshot(Request::get("/program/hello").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let buf = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    let program: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+    assert_eq!(program["invocations"], 1);
+}
+
+#[tokio::test]
+async fn test_replay_after_replica_window() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        replica_window_ms: 100,
+        ..test_config(data_dir.path())
+    };
+    let server = Arc::new(new_node_server_with_config(IcpAgentMock::default(), config));
+    let router = server.clone().router();
+    pull_hello(&router).await;
+
+    let payload = Encode!(&String::from("World")).unwrap();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let request = || signed_request("hello", "hello", payload.clone(), now.as_nanos() as u64);
+    let resp = router.clone().oneshot(request()).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // the signature is still within the signature window, but its response is no longer kept
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let resp = router.clone().oneshot(request()).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let program = server.program(&"hello".parse().unwrap()).await.unwrap();
+    assert_eq!(program.invocations, 1);
+}
+
+#[tokio::test]
+async fn test_unsigned_calls_allowed() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        require_signed_calls: false,
+        ..test_config(data_dir.path())
+    };
+    let server = Arc::new(new_node_server_with_config(IcpAgentMock::default(), config));
+    let router = server.router();
+    pull_hello(&router).await;
+
+    let resp = router
+        .clone()
+        .oneshot(
+            Request::post("/procedure")
+                .header(Header::ProgramId.to_string(), "hello")
+                .header(Header::ProgramProc.to_string(), "hello")
+                .body(Body::from(Encode!(&String::from("World")).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // the canister can tell a program that is not loaded apart from a failing node
+    let resp = router
+        .oneshot(
+            Request::post("/procedure")
+                .header(Header::ProgramId.to_string(), "goodbye")
+                .header(Header::ProgramProc.to_string(), "hello")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_program_quota() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let server = node_server(data_dir.path());
+    server
+        .pull_program(PullProgram {
+            canister_id: "hello".to_string(),
+            program_id: Some("hello".to_string()),
+            url: "http://localhost:8000".to_string(),
+            policy: None,
+            quota: Some(CallQuota {
+                rate_per_sec: Some(1),
+                burst: Some(2),
+                max_concurrent_calls: None,
+            }),
+            device_secret: Some(encode_secret(&DEVICE_SECRET)),
+        })
+        .await
+        .unwrap();
+    let router = server.clone().router();
+
+    // the burst is served, the calls past it are turned away until the bucket refills
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let payload = Encode!(&String::from("World")).unwrap();
+    for i in 0..3 {
+        let request = signed_request("hello", "hello", payload.clone(), now.as_nanos() as u64 + i);
+        let resp = router.clone().oneshot(request).await.unwrap();
+        if i < 2 {
+            assert_eq!(resp.status(), StatusCode::OK);
+        } else {
+            assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(resp.headers()["Retry-After"], "1");
+        }
+    }
+
+    let program = server.program(&"hello".parse().unwrap()).await.unwrap();
+    assert_eq!(program.quota.rate_per_sec, Some(1));
+    assert_eq!(program.throttled, 1);
+    assert_eq!(program.invocations, 2);
+
+    // the error names the quota the call is over
+    let hello = "hello".parse().unwrap();
+    let err = server
+        .call_procedure(&hello, "hello", &payload, None)
+        .await
+        .unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("over its quota of 1 calls per second"),
+        "{err}"
+    );
+
+    let data_dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        canister_quota: QuotaLimits {
+            rate_per_sec: Some(1),
+            burst: Some(1),
+            ..Default::default()
+        },
+        ..test_config(data_dir.path())
+    };
+    let server = Arc::new(new_node_server_with_config(IcpAgentMock::default(), config));
+    pull_hello(&server.clone().router()).await;
+    server
+        .call_procedure(&hello, "hello", &payload, None)
+        .await
+        .unwrap();
+    let err = server
+        .call_procedure(&hello, "hello", &payload, None)
+        .await
+        .unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("over the quota of its canister of 1 calls per second"),
+        "{err}"
+    );
+}
+
+#[tokio::test]
+async fn test_admin_routes() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let mut config = test_config(data_dir.path());
+    config.admin.token = Some("admin-token".to_string());
+    let server = Arc::new(new_node_server_with_config(IcpAgentMock::default(), config));
+    let public = server.clone().public_router();
+    let admin = server.admin_router();
+
+    // program management is not served to the canisters
+    for request in [
+        Request::get("/program").body(Body::empty()).unwrap(),
+        Request::get("/metrics").body(Body::empty()).unwrap(),
+    ] {
+        let resp = public.clone().oneshot(request).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+    let resp = public
+        .clone()
+        .oneshot(Request::get("/healthz").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // the admin routes require the token
+    for authorization in [None, Some("Bearer wrong-token"), Some("admin-token")] {
+        let mut request = Request::get("/program");
+        if let Some(authorization) = authorization {
+            request = request.header("Authorization", authorization);
+        }
+        let resp = admin
+            .clone()
+            .oneshot(request.body(Body::empty()).unwrap())
             .await
             .unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(resp.headers()["WWW-Authenticate"], "Bearer");
+    }
 
-        // status ok
-        assert_eq!(resp.status_code, 200);
+    let resp = admin
+        .oneshot(
+            Request::get("/program")
+                .header("Authorization", "Bearer admin-token")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}
 
-        let mut data = resp.data;
-        let mut buf = Vec::new();
-        data.read_to_end(&mut buf).unwrap();
+#[tokio::test]
+async fn test_canister_failures() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let config = test_config(data_dir.path());
+    let agent = IcpAgentImpl::new(&config).unwrap();
+    let router = Arc::new(new_node_server_with_config(agent, config)).router();
 
-        println!("{:?}", buf);
+    // a mistyped canister id is the operator's mistake, an unreachable replica the canister's
+    let unreachable = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let unreachable_url = format!("http://{}", unreachable.local_addr().unwrap());
+    drop(unreachable);
+    for (canister_id, url, status) in [
+        (
+            "not a canister",
+            "http://127.0.0.1:4943",
+            StatusCode::BAD_REQUEST,
+        ),
+        (
+            "bkyz2-fmaaa-aaaaa-qaaaq-cai",
+            "not a url",
+            StatusCode::BAD_REQUEST,
+        ),
+        (
+            "bkyz2-fmaaa-aaaaa-qaaaq-cai",
+            unreachable_url.as_str(),
+            StatusCode::BAD_GATEWAY,
+        ),
+    ] {
+        let payload = serde_json::to_string(&PullProgram {
+            canister_id: canister_id.to_string(),
+            program_id: Some("hello".to_string()),
+            url: url.to_string(),
+            policy: None,
+            quota: None,
+            device_secret: Some(encode_secret(&DEVICE_SECRET)),
+        })
+        .unwrap();
+        let resp = router
+            .clone()
+            .oneshot(
+                Request::post("/program")
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(payload))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), status, "pulling from {canister_id} at {url}");
+    }
 
-        // response should be "Hello, World!"
-        assert_eq!(Decode!(&buf, String).unwrap(), "Hello, World!");
+    // the node keeps running
+    let resp = router
+        .oneshot(Request::get("/healthz").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_missing_program_headers() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let router = node_server(data_dir.path()).router();
+
+    let resp = router
+        .oneshot(
+            Request::post("/procedure")
+                .header(Header::ProgramProc.to_string(), "hello")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    let buf = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    assert!(String::from_utf8_lossy(&buf).contains("Program-Identifier"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_concurrent_procedure_calls() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let router = node_server(data_dir.path()).router();
+    pull_hello(&router).await;
+
+    // each call is served on its own task the same way the server serves connections
+    let calls = (0..8)
+        .map(|n| {
+            let router = router.clone();
+            tokio::spawn(async move {
+                let resp = router
+                    .oneshot(procedure_request(
+                        "hello",
+                        "hello",
+                        Encode!(&format!("World {n}")).unwrap(),
+                    ))
+                    .await
+                    .unwrap();
+                assert_eq!(resp.status(), StatusCode::OK);
+
+                let buf = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+                assert_eq!(Decode!(&buf, String).unwrap(), format!("Hello, World {n}!"));
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for call in calls {
+        call.await.unwrap();
     }
 }
+
+#[tokio::test]
+async fn test_node_identity() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let config = test_config(data_dir.path());
+
+    // the identity generated on first start is kept across restarts
+    let principal = IcpAgentImpl::new(&config).unwrap().principal();
+    assert!(data_dir.path().join("identity.pem").exists());
+    assert_eq!(IcpAgentImpl::new(&config).unwrap().principal(), principal);
+    assert_ne!(principal, candid::Principal::anonymous());
+
+    // a configured identity is read as it is
+    let config = Config {
+        identity_pem: Some(data_dir.path().join("identity.pem")),
+        data_dir: data_dir.path().join("other"),
+        ..Default::default()
+    };
+    assert_eq!(IcpAgentImpl::new(&config).unwrap().principal(), principal);
+
+    // and never generated in its place
+    let config = Config {
+        identity_pem: Some(data_dir.path().join("missing.pem")),
+        ..Default::default()
+    };
+    let err = IcpAgentImpl::new(&config).err().unwrap();
+    assert_eq!(err.status_code(), 400);
+    assert!(!data_dir.path().join("missing.pem").exists());
+
+    std::fs::write(data_dir.path().join("invalid.pem"), "not a key").unwrap();
+    let config = Config {
+        identity_pem: Some(data_dir.path().join("invalid.pem")),
+        ..Default::default()
+    };
+    assert_eq!(IcpAgentImpl::new(&config).err().unwrap().status_code(), 400);
+}
+
+#[tokio::test]
+async fn test_program_discovery() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let server = node_server(data_dir.path());
+
+    // the program is pulled under the id the canister declares
+    let pulled = server
+        .pull_program(PullProgram {
+            canister_id: "hello".to_string(),
+            program_id: None,
+            url: "http://localhost:8000".to_string(),
+            policy: None,
+            quota: None,
+            device_secret: Some(encode_secret(&DEVICE_SECRET)),
+        })
+        .await
+        .unwrap();
+    assert_eq!(pulled.program_id, "hello");
+
+    let program = server.program(&"hello".parse().unwrap()).await.unwrap();
+    assert_eq!(program.version.as_deref(), Some("0.1.0"));
+    assert_eq!(program.procedures, ["hello"]);
+
+    // a mistyped id would never receive a call
+    let router = server.router();
+    let (status, _) = pull(&router, "helo").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let resp = router
+        .oneshot(Request::get("/program/helo").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_load_on_demand() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let server_with = |load_on_demand: bool| {
+        let config = Config {
+            canisters: vec![CanisterConfig {
+                canister_id: CANISTER_ID.to_string(),
+                url: "http://localhost:8000".to_string(),
+                program_id: None,
+                device_secret: Some(encode_secret(&DEVICE_SECRET)),
+                limits: Default::default(),
+                load_on_demand,
+                network: None,
+                root_key: None,
+            }],
+            ..test_config(data_dir.path())
+        };
+        Arc::new(new_node_server_with_config(IcpAgentMock::default(), config))
+    };
+    let payload = Encode!(&String::from("World")).unwrap();
+
+    // a canister whose program is not loaded on demand must have it pulled first
+    let router = server_with(false).router();
+    let resp = router
+        .oneshot(procedure_request("hello", "hello", payload.clone()))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    // only the canister can have its program pulled
+    let server = server_with(true);
+    let router = server.clone().router();
+    let mut forged = procedure_request("hello", "hello", payload.clone());
+    *forged.body_mut() = Body::from(Encode!(&String::from("Mallory")).unwrap());
+    let resp = router.clone().oneshot(forged).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert!(server.programs().await.is_empty());
+
+    // the first call pulls the program and is served by it
+    let resp = router
+        .clone()
+        .oneshot(procedure_request("hello", "hello", payload.clone()))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let buf = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(Decode!(&buf, String).unwrap(), "Hello, World!");
+    assert_eq!(server.programs().await.len(), 1);
+
+    // another program of the canister is never pulled
+    let resp = router
+        .oneshot(procedure_request("other", "hello", payload))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(server.programs().await.len(), 1);
+}
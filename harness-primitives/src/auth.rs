@@ -0,0 +1,137 @@
+//! The signature canisters put on the procedure calls they make to their devices.
+//!
+//! When a device registers with a canister the two share a secret, the canister signs every call
+//! with it and the device refuses calls it cannot verify. The signature only covers values every
+//! IC replica agrees on, so that the replicas making the outcall send identical requests.
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    error::{Error, Result},
+    http::Header,
+};
+
+/// The headers covered by the signature, in the order they are signed.
+pub const SIGNED_HEADERS: [Header; 3] =
+    [Header::ProgramId, Header::ProgramProc, Header::CanisterId];
+
+/// The size of the secrets shared between devices and canisters, in bytes.
+pub const SECRET_SIZE: usize = 32;
+
+/// The parts of a procedure call covered by its signature.
+pub struct SignedRequest<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    /// The values of the [`SIGNED_HEADERS`], in order.
+    pub headers: &'a [String],
+    pub body: &'a [u8],
+    /// When the call was made, in nanoseconds since the unix epoch.
+    pub timestamp: u64,
+}
+
+impl SignedRequest<'_> {
+    /// The hex encoded HMAC-SHA256 of the request.
+    pub fn sign(&self, secret: &[u8]) -> String {
+        hex::encode(self.mac(secret).finalize().into_bytes())
+    }
+
+    /// Whether the signature is the one of the request, compared in constant time.
+    ///
+    /// Only the lowercase hex encoding produced by [`SignedRequest::sign`] is accepted, so that a
+    /// signature has a single spelling to be recognized by when it is seen again.
+    pub fn verify(&self, secret: &[u8], signature: &str) -> bool {
+        let signature = signature.trim();
+        !signature.bytes().any(|byte| byte.is_ascii_uppercase())
+            && hex::decode(signature)
+                .is_ok_and(|signature| self.mac(secret).verify_slice(&signature).is_ok())
+    }
+
+    fn mac(&self, secret: &[u8]) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any size; qed");
+        mac.update(&self.canonical());
+        mac
+    }
+
+    /// The bytes signed: the method, path, signed headers, timestamp and body hash, one per line.
+    fn canonical(&self) -> Vec<u8> {
+        let mut canonical = format!("{}\n{}\n", self.method.to_uppercase(), self.path);
+        for (header, value) in SIGNED_HEADERS.iter().zip(self.headers) {
+            canonical.push_str(&format!(
+                "{}:{}\n",
+                header.to_string().to_lowercase(),
+                value.trim()
+            ));
+        }
+        canonical.push_str(&format!(
+            "{}\n{}",
+            self.timestamp,
+            hex::encode(Sha256::digest(self.body))
+        ));
+        canonical.into_bytes()
+    }
+}
+
+/// Encodes a secret to hand it to a device.
+pub fn encode_secret(secret: &[u8]) -> String {
+    hex::encode(secret)
+}
+
+/// Decodes a hex encoded secret, as returned by the `register_device` method of the canister.
+pub fn decode_secret(secret: &str) -> Result<Vec<u8>> {
+    hex::decode(secret.trim())
+        .ok()
+        .filter(|secret| secret.len() == SECRET_SIZE)
+        .ok_or_else(|| Error::IO {
+            message: format!("a device secret must be {SECRET_SIZE} hex encoded bytes"),
+            inner: None,
+        })
+}
+
+#[test]
+fn signed_request_covers_every_part() {
+    let secret = [7; SECRET_SIZE];
+    let headers = [
+        "hello".to_string(),
+        "greet".to_string(),
+        "bkyz2-fmaaa-aaaaa-qaaaq-cai".to_string(),
+    ];
+    let request = SignedRequest {
+        method: "POST",
+        path: "/procedure",
+        headers: &headers,
+        body: b"payload",
+        timestamp: 1_700_000_000_000_000_000,
+    };
+    let signature = request.sign(&secret);
+    assert!(request.verify(&secret, &signature));
+    assert!(!request.verify(&[8; SECRET_SIZE], &signature));
+    assert!(!request.verify(&secret, "not hex"));
+    assert!(!request.verify(&secret, &signature.to_uppercase()));
+
+    let other_headers = [
+        "hello".to_string(),
+        "greet".to_string(),
+        "be2us-64aaa-aaaaa-qaabq-cai".to_string(),
+    ];
+    let tampered = [
+        SignedRequest {
+            body: b"tampered",
+            ..request
+        },
+        SignedRequest {
+            timestamp: request.timestamp + 1,
+            ..request
+        },
+        SignedRequest {
+            headers: &other_headers,
+            ..request
+        },
+    ];
+    for request in tampered {
+        assert!(!request.verify(&secret, &signature));
+    }
+
+    assert_eq!(decode_secret(&encode_secret(&secret)).unwrap(), secret);
+    assert!(decode_secret("0707").is_err());
+}
@@ -0,0 +1,229 @@
+#![cfg(feature = "wasm-ext")]
+//! Holds the program instances to their deadline and resource policy.
+//!
+//! The instances run on the waPC provider of `wasmtime-provider`, patched in from `vendor/` with
+//! hooks into the store of an instance: the pool sets the epoch deadline and the fuel of each run
+//! of the guest and attaches a [`ResourceLimiter`] to its store. The waPC host functions are the
+//! upstream ones. A guest stopped at a limit is recognised by the trap it was stopped with.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use anyhow::anyhow;
+use wasmtime_provider::wasmtime::{Module, ResourceLimiter, Trap};
+use wasmtime_provider::{
+    RunBudget, StoreHooks, WasmtimeEngineProviderAsync, WasmtimeEngineProviderAsyncPre,
+    WasmtimeEngineProviderBuilder,
+};
+
+use crate::error::Result;
+use crate::program::ResourcePolicy;
+
+/// The limit of the policy a guest was stopped at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Exhausted {
+    Fuel,
+    Memory,
+    Table,
+}
+
+/// What the last call into an instance used of its policy.
+#[derive(Debug, Default)]
+pub(crate) struct Meter {
+    pub fuel_consumed: u64,
+    pub exhausted: Option<Exhausted>,
+    /// Whether the guest was interrupted for running past its deadline.
+    pub interrupted: bool,
+}
+
+impl Meter {
+    fn exhaust(&mut self, exhausted: Exhausted) {
+        // the first limit reached is the one that stopped the guest
+        self.exhausted.get_or_insert(exhausted);
+    }
+}
+
+/// Locks the meter shared between an instance and its pool.
+pub(crate) fn lock(meter: &Mutex<Meter>) -> MutexGuard<'_, Meter> {
+    meter.lock().expect("lock is not poisoned; qed")
+}
+
+/// Stops the guest growing its memory or tables past the limits of the policy.
+struct Limiter {
+    policy: ResourcePolicy,
+    meter: Arc<Mutex<Meter>>,
+}
+
+impl ResourceLimiter for Limiter {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> anyhow::Result<bool> {
+        match self.policy.max_memory_bytes {
+            Some(max) if desired as u64 > max => {
+                lock(&self.meter).exhaust(Exhausted::Memory);
+                Err(anyhow!(
+                    "the memory cannot grow to {desired} bytes, the limit is {max} bytes"
+                ))
+            }
+            _ => Ok(true),
+        }
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: u32,
+        desired: u32,
+        _maximum: Option<u32>,
+    ) -> anyhow::Result<bool> {
+        match self.policy.max_table_elements {
+            Some(max) if desired > max => {
+                lock(&self.meter).exhaust(Exhausted::Table);
+                Err(anyhow!(
+                    "the table cannot grow to {desired} elements, the limit is {max} elements"
+                ))
+            }
+            _ => Ok(true),
+        }
+    }
+}
+
+/// Creates the instances of a compiled program, all held to the same policy.
+pub(crate) struct ProgramPre {
+    pre: WasmtimeEngineProviderAsyncPre,
+    policy: ResourcePolicy,
+}
+
+impl ProgramPre {
+    pub fn new(module: &Module, policy: &ResourcePolicy) -> Result<Self> {
+        let pre = WasmtimeEngineProviderBuilder::new()
+            .engine(module.engine().clone())
+            .module(module.clone())
+            .build_async_pre()?;
+
+        Ok(Self {
+            pre,
+            policy: policy.clone(),
+        })
+    }
+
+    /// An instance reporting what its calls use to the meter, each run of the guest is
+    /// interrupted after the number of epoch ticks held by `deadline_ticks` when it starts.
+    pub fn rehydrate(
+        &self,
+        meter: Arc<Mutex<Meter>>,
+        deadline_ticks: Arc<AtomicU64>,
+    ) -> Result<WasmtimeEngineProviderAsync> {
+        Ok(self.pre.rehydrate_with_hooks(Arc::new(Budget {
+            policy: self.policy.clone(),
+            deadline_ticks,
+            meter,
+        }))?)
+    }
+}
+
+/// Gives each run of the guest its deadline and, when the program is held to a policy, its fuel.
+struct Budget {
+    policy: ResourcePolicy,
+    deadline_ticks: Arc<AtomicU64>,
+    meter: Arc<Mutex<Meter>>,
+}
+
+impl Budget {
+    /// The fuel of a run, only the engine of the programs held to a policy consumes fuel.
+    fn fuel(&self) -> Option<u64> {
+        (!self.policy.is_unlimited()).then(|| self.policy.fuel_per_call.unwrap_or(u64::MAX))
+    }
+}
+
+impl StoreHooks for Budget {
+    fn limiter(&self) -> Option<Box<dyn ResourceLimiter + Send + Sync>> {
+        Some(Box::new(Limiter {
+            policy: self.policy.clone(),
+            meter: self.meter.clone(),
+        }))
+    }
+
+    fn before_run(&self) -> RunBudget {
+        *lock(&self.meter) = Meter::default();
+        RunBudget {
+            epoch_deadline: Some(self.deadline_ticks.load(Ordering::Relaxed)),
+            fuel: self.fuel(),
+        }
+    }
+
+    /// Records the fuel consumed and the limit the guest was stopped at, the limiter records the
+    /// memory and table limits itself.
+    fn after_run(&self, fuel_left: Option<u64>, error: Option<&anyhow::Error>) {
+        let mut meter = lock(&self.meter);
+        if let Some(fuel) = self.fuel() {
+            meter.fuel_consumed = fuel - fuel_left.unwrap_or_default();
+        }
+        match error.and_then(|err| err.downcast_ref::<Trap>()) {
+            Some(Trap::OutOfFuel) => meter.exhaust(Exhausted::Fuel),
+            Some(Trap::Interrupt) => meter.interrupted = true,
+            _ => {}
+        }
+    }
+}
+
+/// A waPC guest answering every call with an empty response.
+#[cfg(test)]
+const EMPTY_WAT: &str = r#"
+    (module
+        (import "wapc" "__guest_response" (func $guest_response (param i32 i32)))
+        (memory (export "memory") 1)
+        (func (export "__guest_call") (param i32 i32) (result i32)
+            (call $guest_response (i32.const 0) (i32.const 0))
+            (i32.const 1)))
+"#;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn provider_sets_the_deadline_of_each_call() {
+    let module = Module::new(&crate::harness_os::engine(false).unwrap(), EMPTY_WAT).unwrap();
+    let meter = Arc::<Mutex<Meter>>::default();
+    let deadline_ticks = Arc::new(AtomicU64::new(u64::MAX / 2));
+    let provider = ProgramPre::new(&module, &ResourcePolicy::default())
+        .unwrap()
+        .rehydrate(meter.clone(), deadline_ticks.clone())
+        .unwrap();
+    let host = wapc::WapcHostAsync::new(Box::new(provider), None)
+        .await
+        .unwrap();
+
+    // the same instance is interrupted at once, then runs again under a later deadline
+    for (ticks, interrupted) in [(u64::MAX / 2, false), (0, true), (u64::MAX / 2, false)] {
+        deadline_ticks.store(ticks, Ordering::Relaxed);
+        assert_eq!(host.call("echo", b"ping").await.is_err(), interrupted);
+        assert_eq!(lock(&meter).interrupted, interrupted);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn provider_meters_the_fuel_of_each_call() {
+    let module = Module::new(&crate::harness_os::engine(true).unwrap(), EMPTY_WAT).unwrap();
+    let meter = Arc::<Mutex<Meter>>::default();
+    let pre = ProgramPre::new(
+        &module,
+        &ResourcePolicy {
+            fuel_per_call: Some(1_000),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let host = wapc::WapcHostAsync::new(
+        Box::new(
+            pre.rehydrate(meter.clone(), Arc::new(AtomicU64::new(u64::MAX / 2)))
+                .unwrap(),
+        ),
+        None,
+    )
+    .await
+    .unwrap();
+
+    assert!(host.call("echo", b"ping").await.is_ok());
+    let meter = lock(&meter);
+    assert!(meter.fuel_consumed > 0 && meter.fuel_consumed < 1_000);
+    assert_eq!(meter.exhausted, None);
+}
@@ -1,4 +1,6 @@
 //! Error handling primitives for the harness project.
+use std::time::Duration;
+
 use thiserror::Error;
 
 #[cfg(feature = "wasm-ext")]
@@ -26,6 +28,48 @@ pub enum Error {
         #[source]
         inner: Option<Box<dyn std::error::Error + Send + Sync>>,
     },
+
+    /// The device cannot take on the work right now, the caller is free to retry later.
+    #[error("Busy: {message}")]
+    Busy { message: String },
+
+    /// The caller made more calls than the quota of the program allows, it may retry once
+    /// `retry_after` has passed.
+    #[error("Rate limited: {message}")]
+    RateLimited {
+        message: String,
+        retry_after: Duration,
+    },
+
+    /// The call did not finish within its deadline.
+    #[error("Timeout: {message}")]
+    Timeout { message: String },
+
+    /// The program ran into a limit of its resource policy, memory, tables or fuel.
+    #[error("Resource exhausted: {message}")]
+    ResourceExhausted { message: String },
+
+    /// The program pulled from the canister cannot be verified, it is not the one the canister
+    /// published or not signed by a publisher the device trusts.
+    #[error("Integrity error: {message}")]
+    Integrity { message: String },
+
+    /// The canister could not be reached through its replica, rejected the call or answered
+    /// something unexpected.
+    #[error("Canister error: {message}")]
+    Canister {
+        message: String,
+        #[source]
+        inner: Option<Box<dyn std::error::Error + Send + Sync>>,
+    },
+
+    /// The call is not signed by the canister the device is registered with.
+    #[error("Unauthorized: {message}")]
+    Unauthorized { message: String },
+
+    /// The requested resource does not exist on the device.
+    #[error("Not found: {message}")]
+    NotFound { message: String },
 }
 
 impl Error {
@@ -39,11 +83,44 @@ impl Error {
         }
     }
 
+    /// The HTTP status code used to answer a client with this error.
+    pub fn status_code(&self) -> u16 {
+        match self {
+            Self::IO { .. } => 400,
+            Self::Internal { .. } | Self::Custom(_) => 500,
+            Self::Unauthorized { .. } => 401,
+            Self::NotFound { .. } => 404,
+            Self::Busy { .. } => 503,
+            Self::RateLimited { .. } => 429,
+            Self::Timeout { .. } => 504,
+            Self::ResourceExhausted { .. } => 422,
+            Self::Integrity { .. } | Self::Canister { .. } => 502,
+        }
+    }
+
+    /// A short, stable name for the kind of error, used to label metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Custom(_) => "custom",
+            Self::IO { .. } => "io",
+            Self::Internal { .. } => "internal",
+            Self::Busy { .. } => "busy",
+            Self::RateLimited { .. } => "rate_limited",
+            Self::Timeout { .. } => "timeout",
+            Self::ResourceExhausted { .. } => "resource_exhausted",
+            Self::Integrity { .. } => "integrity",
+            Self::Canister { .. } => "canister",
+            Self::Unauthorized { .. } => "unauthorized",
+            Self::NotFound { .. } => "not_found",
+        }
+    }
+
+    /// A failure of the device rather than of the caller, answered with `500`.
     pub fn internal<T>(message: &str, err: Option<T>) -> Self
     where
         T: Into<Box<dyn std::error::Error + Send + Sync>>,
     {
-        Self::IO {
+        Self::Internal {
             message: message.into(),
             inner: err.map(|val| val.into()),
         }
@@ -94,3 +171,21 @@ impl From<WasmtimeError> for Error {
         }
     }
 }
+
+#[test]
+fn internal_errors_are_not_blamed_on_the_caller() {
+    assert_eq!(
+        Error::internal::<anyhow::Error>("failed", None).status_code(),
+        500
+    );
+    assert_eq!(
+        Error::io::<anyhow::Error>("failed", None).status_code(),
+        400
+    );
+
+    #[cfg(feature = "wasm-ext")]
+    assert_eq!(
+        Error::from(WapcError::General("failed".to_string())).status_code(),
+        500
+    );
+}
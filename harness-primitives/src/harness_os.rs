@@ -1,27 +1,371 @@
 #![cfg(feature = "wasm-ext")]
 //! The Harness OS is the system that manages harness programs on the device. It is responsible for loading, unloading, and executing programs.
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
 
+use tokio::sync::Semaphore;
 use wapc::WapcHostAsync;
+use wasmtime_provider::wasmtime::{Config as EngineConfig, Engine, Module};
 
+use crate::engine::{self, Exhausted, Meter, ProgramPre};
 use crate::error::{Error, Result};
-use crate::program::ProgramId;
+use crate::program::{ProgramId, ResourcePolicy};
+
+/// The settings used when loading a program to the device.
+#[derive(Clone, Debug)]
+pub struct ProgramConfig {
+    /// The number of instances created from the compiled program, this is the number of calls
+    /// into the program that can run in parallel.
+    pub pool_size: usize,
+    /// How long a call waits for an idle instance before it fails with a busy error.
+    /// When `None` the call waits until an instance is available.
+    pub checkout_timeout: Option<Duration>,
+    /// How long a call may run before the guest is interrupted and the call fails with a timeout
+    /// error. When `None` calls run until they finish.
+    pub call_timeout: Option<Duration>,
+    /// The memory, tables and fuel the program may use.
+    pub policy: ResourcePolicy,
+}
+
+impl Default for ProgramConfig {
+    fn default() -> Self {
+        Self {
+            pool_size: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            checkout_timeout: Some(Duration::from_secs(30)),
+            call_timeout: Some(Duration::from_secs(30)),
+            policy: ResourcePolicy::default(),
+        }
+    }
+}
+
+/// How often the epoch of the engines is incremented, call deadlines are rounded up to it.
+pub const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+/// The engine shared by all programs, or by the programs held to a resource policy when
+/// `metered`, whose guests consume fuel as they run. The epoch of both is incremented every
+/// [`EPOCH_TICK`] so that guests running past their deadline are interrupted.
+pub(crate) fn engine(metered: bool) -> Result<Engine> {
+    static ENGINES: OnceLock<[Engine; 2]> = OnceLock::new();
+    if let Some(engines) = ENGINES.get() {
+        return Ok(engines[metered as usize].clone());
+    }
+
+    let new_engine = |consume_fuel| {
+        let mut config = EngineConfig::default();
+        config
+            .async_support(true)
+            .epoch_interruption(true)
+            .consume_fuel(consume_fuel);
+        Engine::new(&config)
+            .map_err(|err| Error::Custom(err.context("failed to create the wasm engine")))
+    };
+    let engines = [new_engine(false)?, new_engine(true)?];
+
+    Ok(ENGINES.get_or_init(|| {
+        let tickers = engines.clone();
+        std::thread::Builder::new()
+            .name("harness-epoch".to_string())
+            .spawn(move || loop {
+                std::thread::sleep(EPOCH_TICK);
+                tickers.iter().for_each(Engine::increment_epoch);
+            })
+            .expect("the epoch thread is spawned; qed");
+        engines
+    })[metered as usize]
+        .clone())
+}
+
+/// The number of epoch ticks a guest may run for.
+fn deadline_ticks(timeout: Option<Duration>) -> u64 {
+    match timeout {
+        // the epoch is incremented at most one tick after the call starts, hence the extra tick
+        Some(timeout) => timeout.as_nanos().div_ceil(EPOCH_TICK.as_nanos()) as u64 + 1,
+        // far enough to never be reached, low enough for the deadline not to overflow
+        None => u64::MAX / 2,
+    }
+}
+
+/// The calls made into a program since it was loaded.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProgramStats {
+    /// The number of calls made into the program.
+    pub invocations: u64,
+    /// The number of calls that failed, including the calls that found no idle instance.
+    pub failures: u64,
+    /// The error of the last failed call.
+    pub last_error: Option<String>,
+}
+
+/// The response of a call along with the resources it used.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Invocation {
+    pub response: Vec<u8>,
+    /// The fuel consumed by the guest, roughly the number of instructions it executed. `None`
+    /// when the program is not held to a resource policy.
+    pub fuel_consumed: Option<u64>,
+}
+
+/// An instance of the program along with what its calls use and the deadline of its next call.
+struct Instance {
+    host: WapcHostAsync,
+    meter: Arc<Mutex<Meter>>,
+    deadline_ticks: Arc<AtomicU64>,
+}
+
+/// A pool of instances created from one compiled program.
+///
+/// A waPC host keeps the in-flight request in shared module state, so an instance serves a single
+/// call at a time. Calls check out an idle instance and return it once done.
+pub struct ProgramPool {
+    pre: ProgramPre,
+    idle: Mutex<Vec<Instance>>,
+    permits: Semaphore,
+    size: usize,
+    checkout_timeout: Option<Duration>,
+    call_timeout: Option<Duration>,
+    policy: ResourcePolicy,
+    invocations: AtomicU64,
+    failures: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl ProgramPool {
+    /// Compiles the program once and creates the configured number of instances from it.
+    pub async fn new(program: &[u8], config: &ProgramConfig) -> Result<Self> {
+        if config.pool_size == 0 {
+            return Err(Error::io::<anyhow::Error>(
+                "the pool size must be at least 1",
+                None,
+            ));
+        }
+
+        let module = Module::new(&engine(!config.policy.is_unlimited())?, program)
+            .map_err(|err| Error::Custom(err.context("failed to compile the program")))?;
+        let pool = Self {
+            pre: ProgramPre::new(&module, &config.policy)?,
+            idle: Mutex::new(Vec::with_capacity(config.pool_size)),
+            permits: Semaphore::new(config.pool_size),
+            size: config.pool_size,
+            checkout_timeout: config.checkout_timeout,
+            call_timeout: config.call_timeout,
+            policy: config.policy.clone(),
+            invocations: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            last_error: Mutex::new(None),
+        };
+
+        for _ in 0..pool.size {
+            let instance = pool.instantiate().await?;
+            pool.idle
+                .lock()
+                .expect("lock is not poisoned; qed")
+                .push(instance);
+        }
+
+        Ok(pool)
+    }
+
+    /// Calls the operation on an idle instance, waiting for one if all are in use.
+    pub async fn call(&self, operation: &str, payload: &[u8]) -> Result<Vec<u8>> {
+        self.call_with_timeout(operation, payload, None).await
+    }
+
+    /// Calls the operation with a deadline of its own, the program's call timeout still bounds
+    /// the call when it is shorter.
+    pub async fn call_with_timeout(
+        &self,
+        operation: &str,
+        payload: &[u8],
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        Ok(self.invoke(operation, payload, timeout).await?.response)
+    }
+
+    /// Calls the operation like [`Self::call_with_timeout`], reporting the fuel consumed by the
+    /// call along with its response.
+    pub async fn invoke(
+        &self,
+        operation: &str,
+        payload: &[u8],
+        timeout: Option<Duration>,
+    ) -> Result<Invocation> {
+        self.invocations.fetch_add(1, Ordering::Relaxed);
+        let result = self.checkout_and_call(operation, payload, timeout).await;
+        if let Err(err) = &result {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+            *self.last_error.lock().expect("lock is not poisoned; qed") = Some(err.to_string());
+        }
+
+        result
+    }
+
+    async fn checkout_and_call(
+        &self,
+        operation: &str,
+        payload: &[u8],
+        timeout: Option<Duration>,
+    ) -> Result<Invocation> {
+        let _permit = match self.checkout_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.permits.acquire())
+                .await
+                .map_err(|_| Error::Busy {
+                    message: format!("all {} program instances are in use", self.size),
+                })?,
+            None => self.permits.acquire().await,
+        }
+        .map_err(|err| Error::Internal {
+            message: "the program pool has been closed".to_string(),
+            inner: Some(Box::new(err)),
+        })?;
+
+        // An instance that was dropped mid-call is not returned to the pool, its permit is
+        // then used to create a replacement here.
+        let idle = self.idle.lock().expect("lock is not poisoned; qed").pop();
+        let instance = match idle {
+            Some(instance) => instance,
+            None => self.instantiate().await?,
+        };
+
+        // the deadline of the call is set on the store of the instance when the guest runs
+        let timeout = match (timeout, self.call_timeout) {
+            (Some(timeout), Some(max)) => Some(timeout.min(max)),
+            (timeout, max) => timeout.or(max),
+        };
+        instance
+            .deadline_ticks
+            .store(deadline_ticks(timeout), Ordering::Relaxed);
+        let result = self
+            .call_instance(&instance, operation, payload, timeout)
+            .await;
+        // the guest was stopped at an arbitrary point, its state cannot be trusted so the
+        // instance is dropped and replaced on a later call
+        if !matches!(
+            result,
+            Err(Error::Timeout { .. } | Error::ResourceExhausted { .. })
+        ) {
+            self.idle
+                .lock()
+                .expect("lock is not poisoned; qed")
+                .push(instance);
+        }
+
+        result
+    }
+
+    async fn call_instance(
+        &self,
+        instance: &Instance,
+        operation: &str,
+        payload: &[u8],
+        timeout: Option<Duration>,
+    ) -> Result<Invocation> {
+        let result = instance.host.call(operation, payload).await;
+        let (fuel_consumed, exhausted, interrupted) = {
+            let meter = engine::lock(&instance.meter);
+            (
+                (!self.policy.is_unlimited()).then_some(meter.fuel_consumed),
+                meter.exhausted,
+                meter.interrupted,
+            )
+        };
+
+        if let Some(exhausted) = exhausted {
+            return Err(self.exhausted_error(exhausted));
+        }
+        if interrupted {
+            return Err(Error::Timeout {
+                message: format!(
+                    "the call did not finish within {}ms",
+                    timeout.unwrap_or_default().as_millis()
+                ),
+            });
+        }
+        Ok(Invocation {
+            response: result?,
+            fuel_consumed,
+        })
+    }
+
+    /// The number of instances in the pool.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The number of instances currently serving calls.
+    pub fn in_use(&self) -> usize {
+        self.size - self.permits.available_permits()
+    }
+
+    /// The calls made into the program since it was loaded.
+    pub fn stats(&self) -> ProgramStats {
+        ProgramStats {
+            invocations: self.invocations.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            last_error: self
+                .last_error
+                .lock()
+                .expect("lock is not poisoned; qed")
+                .clone(),
+        }
+    }
+
+    /// The resources the program may use.
+    pub fn policy(&self) -> &ResourcePolicy {
+        &self.policy
+    }
+
+    /// A new instance of the program, initialized within the deadline of a call.
+    async fn instantiate(&self) -> Result<Instance> {
+        let meter = Arc::<Mutex<Meter>>::default();
+        let deadline_ticks = Arc::new(AtomicU64::new(deadline_ticks(self.call_timeout)));
+        let provider = self
+            .pre
+            .rehydrate(Arc::clone(&meter), Arc::clone(&deadline_ticks))?;
+        match WapcHostAsync::new(Box::new(provider), None).await {
+            Ok(host) => Ok(Instance {
+                host,
+                meter,
+                deadline_ticks,
+            }),
+            Err(err) => match engine::lock(&meter).exhausted {
+                Some(exhausted) => Err(self.exhausted_error(exhausted)),
+                None => Err(err.into()),
+            },
+        }
+    }
+
+    fn exhausted_error(&self, exhausted: Exhausted) -> Error {
+        Error::ResourceExhausted {
+            message: match exhausted {
+                Exhausted::Fuel => format!(
+                    "the call ran out of fuel, its budget is {}",
+                    self.policy.fuel_per_call.unwrap_or_default()
+                ),
+                Exhausted::Memory => format!(
+                    "the program exceeded its memory limit of {} bytes",
+                    self.policy.max_memory_bytes.unwrap_or_default()
+                ),
+                Exhausted::Table => format!(
+                    "the program exceeded its table limit of {} elements",
+                    self.policy.max_table_elements.unwrap_or_default()
+                ),
+            },
+        }
+    }
+}
 
 /// Holds all the harness programs that have been loaded to the device.
 #[derive(Default)]
-pub struct HarnessOs(HashMap<ProgramId, WapcHostAsync>);
+pub struct HarnessOs(HashMap<ProgramId, Arc<ProgramPool>>);
 
 impl HarnessOs {
     /// This is responsible for instantiating the host process needed to load the program
     pub async fn new(program_id: ProgramId, program: &[u8]) -> Result<Self> {
-        let engine = wasmtime_provider::WasmtimeEngineProviderBuilder::new()
-            .module_bytes(program)
-            .build_async()?;
-
-        Ok(Self(HashMap::from([(
-            program_id,
-            WapcHostAsync::new(Box::new(engine), None).await?,
-        )])))
+        let mut harness_os = Self::default();
+        harness_os.add_program(program_id, program).await?;
+        Ok(harness_os)
     }
 
     /// Returns the list of program identifiers that are currently loaded in the device.
@@ -29,6 +373,12 @@ impl HarnessOs {
         self.0.keys().cloned().collect()
     }
 
+    /// Returns the instance pool of a loaded program. The pool can be called into without
+    /// holding on to the Harness OS.
+    pub fn program(&self, program_id: &ProgramId) -> Option<Arc<ProgramPool>> {
+        self.0.get(program_id).cloned()
+    }
+
     /// This calls the operation and returns the result or appropriate errors to the caller.
     /// Note that serde to/from bytes is done inherently in the compiled program which uses candid
     pub async fn call_operation(
@@ -38,27 +388,44 @@ impl HarnessOs {
         payload: &[u8],
     ) -> Result<Vec<u8>> {
         match self.0.get(program_id) {
-            Some(program) => Ok(program.call(operation, payload).await?),
-            None => Err(Error::Internal {
-                message: "the program could not be found".to_string(),
-                inner: None,
+            Some(program) => program.call(operation, payload).await,
+            None => Err(Error::NotFound {
+                message: format!("the program '{}' is not loaded", program_id.as_str()),
             }),
         }
     }
 
-    /// Adds a new program to the device.
+    /// Adds a new program to the device using the default [`ProgramConfig`].
     pub async fn add_program(&mut self, program_id: ProgramId, program: &[u8]) -> Result<()> {
-        let engine = wasmtime_provider::WasmtimeEngineProviderBuilder::new()
-            .module_bytes(program)
-            .build_async()?;
-
-        _ = self.0.insert(
-            program_id,
-            WapcHostAsync::new(Box::new(engine), None).await?,
-        );
+        self.add_program_with_config(program_id, program, &ProgramConfig::default())
+            .await
+    }
+
+    /// Adds a new program to the device, creating its instance pool from the config. A program
+    /// already loaded with the same id is upgraded, see [`Self::swap_program`].
+    pub async fn add_program_with_config(
+        &mut self,
+        program_id: ProgramId,
+        program: &[u8],
+        config: &ProgramConfig,
+    ) -> Result<()> {
+        let pool = ProgramPool::new(program, config).await?;
+        _ = self.swap_program(program_id, Arc::new(pool));
         Ok(())
     }
 
+    /// Swaps in a program whose pool was created beforehand, returning the version it replaced.
+    ///
+    /// Creating the pool compiles and initializes the new version, so a version that fails to do
+    /// so never replaces the loaded one. Calls already holding the replaced pool finish on it.
+    pub fn swap_program(
+        &mut self,
+        program_id: ProgramId,
+        pool: Arc<ProgramPool>,
+    ) -> Option<Arc<ProgramPool>> {
+        self.0.insert(program_id, pool)
+    }
+
     /// Removes a program from the set, noop if not found.
     pub fn remove_program(&mut self, program_id: &ProgramId) {
         let _ = self.0.remove(program_id);
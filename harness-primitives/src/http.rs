@@ -1,6 +1,6 @@
 use std::fmt::{Display, Formatter};
 #[cfg(feature = "wasm-ext")]
-use std::{collections::HashMap, io::Cursor};
+use std::io::Cursor;
 
 use candid::{CandidType, Deserialize};
 
@@ -11,6 +11,11 @@ use tokio::io::{
 };
 
 use crate::error::Error;
+use crate::program::{CallQuota, ResourcePolicy};
+
+/// The version of the API spoken between the harness canister and the harness node, bumped on
+/// breaking changes.
+pub const PROTOCOL_VERSION: u32 = 3;
 
 // This struct is legacy code and is not really used in the code.
 #[derive(serde::Serialize, serde:: Deserialize)]
@@ -29,12 +34,42 @@ pub struct Request {
     pub data: Vec<u8>,
 }
 
+impl Request {
+    /// Whether the client wants the connection kept open after the response, HTTP/1.1 keeps
+    /// connections open unless `Connection: close` is sent.
+    pub fn keep_alive(&self) -> bool {
+        !has_connection_option("close", &self.headers)
+    }
+}
+
+fn has_connection_option(option: &str, headers: &[HeaderField]) -> bool {
+    get_header_values("Connection", headers)
+        .iter()
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case(option))
+}
+
 /// This is the payload to the harness node to poll an IC canister from the IC network.
 #[derive(Serialize, Deserialize)]
 pub struct PullProgram {
     pub canister_id: String,
-    pub program_id: String,
+    /// The id the canister calls the program by, queried from the canister when unset. An id
+    /// that differs from the one the canister declares is refused.
+    #[serde(default)]
+    pub program_id: Option<String>,
     pub url: String,
+    /// Limits the resources of the program, a limit configured on the node still applies when
+    /// it is tighter.
+    #[serde(default)]
+    pub policy: Option<ResourcePolicy>,
+    /// Limits the rate and the concurrency of the calls into the program, a quota configured on
+    /// the node still applies when it is tighter.
+    #[serde(default)]
+    pub quota: Option<CallQuota>,
+    /// The hex encoded secret the canister issued to the device when it registered, used to
+    /// verify the calls the canister makes.
+    #[serde(default)]
+    pub device_secret: Option<String>,
 }
 
 #[cfg(feature = "wasm-ext")]
@@ -48,14 +83,10 @@ pub struct Response<T: AsyncRead + Unpin> {
 #[cfg(feature = "wasm-ext")]
 impl From<crate::error::Error> for Response<Cursor<Vec<u8>>> {
     fn from(value: crate::error::Error) -> Self {
-        let status_code = match &value {
-            Error::IO { .. } => 400,
-            Error::Internal { .. } | Error::Custom(_) => 500,
-        };
-
+        let status_code = value.status_code();
         let val_str = value.to_string();
         Self {
-            status_code: status_code as u16,
+            status_code,
             headers: vec![HeaderField(
                 "Content-Type".to_string(),
                 "text/plain".to_string(),
@@ -66,20 +97,21 @@ impl From<crate::error::Error> for Response<Cursor<Vec<u8>>> {
 }
 
 #[cfg(feature = "wasm-ext")]
-impl Response<Cursor<Vec<u8>>> {
-    pub fn hello() -> Self {
-        let data = b"Hello, World!";
-
+impl From<HttpError> for Response<Cursor<Vec<u8>>> {
+    fn from(value: HttpError) -> Self {
         Self {
-            status_code: 202,
+            status_code: value.status_code,
             headers: vec![HeaderField(
                 "Content-Type".to_string(),
                 "text/plain".to_string(),
             )],
-            data: Cursor::new(data.to_vec()),
+            data: Cursor::new(value.to_string().into_bytes()),
         }
     }
+}
 
+#[cfg(feature = "wasm-ext")]
+impl Response<Cursor<Vec<u8>>> {
     pub fn from_bytes(status_code: u16, data: &[u8]) -> Self {
         let string = String::from;
         let headers = vec![
@@ -93,6 +125,25 @@ impl Response<Cursor<Vec<u8>>> {
             data: Cursor::new(data.to_vec()),
         }
     }
+
+    /// Sets the `Content-Length` and `Connection` headers so the client can tell where the
+    /// response ends and whether to send the next request on the same connection.
+    pub fn with_connection(mut self, keep_alive: bool) -> Self {
+        let length = self.data.get_ref().len();
+        self.headers.retain(|header| {
+            !header.0.eq_ignore_ascii_case("Content-Length")
+                && !header.0.eq_ignore_ascii_case("Connection")
+        });
+
+        let string = String::from;
+        self.headers
+            .push(HeaderField(string("Content-Length"), length.to_string()));
+        self.headers.push(HeaderField(
+            string("Connection"),
+            string(if keep_alive { "keep-alive" } else { "close" }),
+        ));
+        self
+    }
 }
 
 #[cfg(feature = "wasm-ext")]
@@ -101,12 +152,15 @@ impl<T: AsyncRead + Unpin + Send> Response<T> {
         let headers = self
             .headers
             .iter()
-            .map(|header| format!("{}: {}", header.0, header.1))
-            .collect::<Vec<_>>()
-            .join("\r\n");
-
-        // https://datatracker.ietf.org/doc/html/rfc2616#section-6
-        format!("HTTP/1.1 {}\r\n{headers}\r\n\r\n", self.status_code)
+            .map(|header| format!("{}: {}\r\n", header.0, header.1))
+            .collect::<String>();
+
+        // https://datatracker.ietf.org/doc/html/rfc9112#section-4
+        format!(
+            "HTTP/1.1 {} {}\r\n{headers}\r\n",
+            self.status_code,
+            reason_phrase(self.status_code)
+        )
     }
 
     pub async fn write<S: AsyncWrite + Unpin + Send>(
@@ -118,11 +172,36 @@ impl<T: AsyncRead + Unpin + Send> Response<T> {
             .await?;
 
         tokio::io::copy(&mut self.data, stream).await?;
+        stream.flush().await?;
 
         Ok(())
     }
 }
 
+#[cfg(feature = "wasm-ext")]
+fn reason_phrase(status_code: u16) -> &'static str {
+    match status_code {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        422 => "Unprocessable Content",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "",
+    }
+}
+
 pub enum Header {
     /// The program identifier
     ProgramId,
@@ -130,6 +209,18 @@ pub enum Header {
     ProgramProc,
     /// The URL of the harness node
     DeviceUrl,
+    /// The deadline of the call in milliseconds, bounded by the deadline of the program
+    ProgramTimeout,
+    /// Correlates a request with the node logs, generated by the node when the client sends none
+    RequestId,
+    /// The fuel consumed by the call, set on the response
+    FuelConsumed,
+    /// When the canister made the call, in nanoseconds since the unix epoch
+    Timestamp,
+    /// The signature of the call by the canister, see [`crate::auth`]
+    Signature,
+    /// The principal of the canister making the call, in text form
+    CanisterId,
 }
 
 impl Display for Header {
@@ -138,6 +229,12 @@ impl Display for Header {
             Self::ProgramId => write!(f, "Program-Identifier"),
             Self::ProgramProc => write!(f, "Program-Procedure"),
             Self::DeviceUrl => write!(f, "Device-Url"),
+            Self::ProgramTimeout => write!(f, "Program-Timeout"),
+            Self::RequestId => write!(f, "X-Request-Id"),
+            Self::FuelConsumed => write!(f, "Program-Fuel-Consumed"),
+            Self::Timestamp => write!(f, "Harness-Timestamp"),
+            Self::Signature => write!(f, "Harness-Signature"),
+            Self::CanisterId => write!(f, "Canister-Id"),
         }
     }
 }
@@ -146,7 +243,14 @@ pub enum Status {
     Ok,
     Created,
     BadRequest,
+    Unauthorized,
     NotFound,
+    UnprocessableContent,
+    TooManyRequests,
+    InternalServerError,
+    BadGateway,
+    ServiceUnavailable,
+    GatewayTimeout,
 }
 
 impl Display for Status {
@@ -155,7 +259,14 @@ impl Display for Status {
             Self::Ok => write!(f, "200 OK"),
             Self::Created => write!(f, "201 Created"),
             Self::BadRequest => write!(f, "400 Bad Request"),
+            Self::Unauthorized => write!(f, "401 Unauthorized"),
             Self::NotFound => write!(f, "404 Not Found"),
+            Self::UnprocessableContent => write!(f, "422 Unprocessable Content"),
+            Self::TooManyRequests => write!(f, "429 Too Many Requests"),
+            Self::InternalServerError => write!(f, "500 Internal Server Error"),
+            Self::BadGateway => write!(f, "502 Bad Gateway"),
+            Self::ServiceUnavailable => write!(f, "503 Service Unavailable"),
+            Self::GatewayTimeout => write!(f, "504 Gateway Timeout"),
         }
     }
 }
@@ -185,76 +296,466 @@ impl TryFrom<&str> for Method {
     }
 }
 
+/// The largest request body accepted by [`parse_request`], and by the node by default.
+pub const MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+
+/// A request that does not conform to HTTP/1.1, to be answered with the status code.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("HTTP error: {message}")]
+pub struct HttpError {
+    pub status_code: u16,
+    pub message: String,
+}
+
+/// The longest request line or header line accepted while parsing a request.
+pub const MAX_LINE_LENGTH: usize = 8 * 1024;
+
+/// The most header fields accepted on a single request.
+pub const MAX_HEADERS: usize = 100;
+
+/// Reads a HTTP/1.1 request off the stream, limiting the body to [`MAX_BODY_SIZE`].
+///
+/// Returns `None` when the connection is closed before a request starts, which is how a client
+/// ends a keep-alive connection.
 #[cfg(feature = "wasm-ext")]
 pub async fn parse_request<T: AsyncBufRead + Unpin + Send>(
+    stream: T,
+) -> Result<Option<Request>, HttpError> {
+    parse_request_with_limit(stream, MAX_BODY_SIZE).await
+}
+
+/// Reads a HTTP/1.1 request off the stream, see [RFC 9112](https://datatracker.ietf.org/doc/html/rfc9112).
+///
+/// The body is read in full either from the `Content-Length` or the chunked transfer coding.
+/// Malformed requests are rejected with `400`, requests with a body but no length with `411` and
+/// bodies larger than `max_body_size` with `413`.
+///
+/// HTTP/1.0 requests that do not ask for keep-alive get an explicit `Connection: close` header so
+/// that [`Request::keep_alive`] applies the HTTP/1.0 default.
+#[cfg(feature = "wasm-ext")]
+pub async fn parse_request_with_limit<T: AsyncBufRead + Unpin + Send>(
     mut stream: T,
-) -> crate::error::Result<Request> {
-    let mut line_buffer = String::new();
-    stream.read_line(&mut line_buffer).await?;
+    max_body_size: usize,
+) -> Result<Option<Request>, HttpError> {
+    let mut line = String::new();
 
-    let mut parts = line_buffer.split_whitespace();
+    // robust servers ignore empty lines received ahead of the request line
+    loop {
+        if read_line(&mut stream, &mut line).await? == 0 {
+            return Ok(None);
+        }
+        if !line.is_empty() {
+            break;
+        }
+    }
 
-    let method = parts
-        .next()
-        .ok_or(Error::io::<anyhow::Error>("missing method", None))?
-        .to_string();
+    let mut parts = line.split(' ');
+    let (method, path, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version), None)
+            if !method.is_empty() && !path.is_empty() =>
+        {
+            (method.to_string(), path.to_string(), version.to_string())
+        }
+        _ => return Err(http_error(400, "malformed request line")),
+    };
 
-    let path = parts
-        .next()
-        .ok_or(Error::io::<anyhow::Error>("missing path", None))?
-        .to_string();
+    let http_1_0 = match version.as_str() {
+        "HTTP/1.1" => false,
+        "HTTP/1.0" => true,
+        v if v.starts_with("HTTP/") => {
+            return Err(http_error(505, &format!("unsupported version: {v}")))
+        }
+        _ => return Err(http_error(400, "malformed request line")),
+    };
 
-    let mut headers: HashMap<String, String> = HashMap::new();
-    let mut data = Vec::new();
+    let mut headers = Vec::new();
     loop {
-        line_buffer.clear();
-        stream.read_line(&mut line_buffer).await?;
-        if line_buffer.is_empty() || line_buffer == "\n" || line_buffer == "\r\n" {
-            // fixme: rework this to not depend on content-length. Using `read_to_end` has blocking issues
-            if let Some(mut length) = headers.get("Content-Length").cloned() {
-                length.retain(|c| !c.is_whitespace() && !c.eq(&'\r') && !c.eq(&'\n'));
-                let length = length.parse::<u32>().map_err(|e| Error::IO {
-                    message: "Content-Length using unexpected format".to_string(),
-                    inner: Some(e.into()),
-                })? as usize;
-
-                data = Vec::with_capacity(length);
-                stream.read_buf(&mut data).await?;
-            }
-
+        if read_line(&mut stream, &mut line).await? == 0 {
+            return Err(http_error(
+                400,
+                "connection closed before the end of the headers",
+            ));
+        }
+        if line.is_empty() {
             break;
         }
+        if headers.len() == MAX_HEADERS {
+            return Err(http_error(431, "too many header fields"));
+        }
+        if line.starts_with([' ', '\t']) {
+            return Err(http_error(400, "obsolete line folding is not supported"));
+        }
 
-        let mut comps = line_buffer.split(':');
-        let key = comps
-            .next()
-            .ok_or(Error::io::<anyhow::Error>("missing header name", None))?
-            .to_string();
+        // only the first colon separates the name, values such as urls contain colons too
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| http_error(400, "header field is missing a colon"))?;
 
-        let value = comps
-            .next()
-            .ok_or(Error::io::<anyhow::Error>("missing header value", None))?
-            .to_string();
+        if name.is_empty() || name.contains(|c: char| c.is_whitespace() || c.is_control()) {
+            return Err(http_error(400, "malformed header field name"));
+        }
+
+        headers.push(HeaderField(
+            name.to_string(),
+            value.trim_matches([' ', '\t']).to_string(),
+        ));
+    }
 
-        headers.insert(key, value);
+    if http_1_0 && !has_connection_option("keep-alive", &headers) {
+        headers.push(HeaderField("Connection".to_string(), "close".to_string()));
     }
 
-    let req = Request {
+    let transfer_encoding = get_header_values("Transfer-Encoding", &headers);
+    let content_length = get_header_values("Content-Length", &headers);
+
+    let data = if !transfer_encoding.is_empty() {
+        // a request carrying both is a request smuggling attempt or a broken client
+        if !content_length.is_empty() {
+            return Err(http_error(
+                400,
+                "both Transfer-Encoding and Content-Length were provided",
+            ));
+        }
+
+        let chunked = transfer_encoding
+            .iter()
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .next_back()
+            .is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"));
+
+        if !chunked {
+            return Err(http_error(400, "the final transfer coding must be chunked"));
+        }
+
+        read_chunked_body(&mut stream, max_body_size).await?
+    } else if !content_length.is_empty() {
+        let length = parse_content_length(&content_length)?;
+        if length > max_body_size {
+            return Err(http_error(413, "the request body is too large"));
+        }
+
+        let mut data = vec![0; length];
+        stream
+            .read_exact(&mut data)
+            .await
+            .map_err(|_| http_error(400, "connection closed before the end of the body"))?;
+        data
+    } else if method.eq_ignore_ascii_case("POST") {
+        return Err(http_error(411, "Content-Length is required"));
+    } else {
+        Vec::new()
+    };
+
+    Ok(Some(Request {
         method,
         path,
-        headers: headers
-            .into_iter()
-            .map(|(k, v)| HeaderField(k, v))
-            .collect::<Vec<HeaderField>>(),
+        headers,
         data,
-    };
+    }))
+}
+
+/// Reads a single line into `line` without the line ending, returning the number of bytes read.
+#[cfg(feature = "wasm-ext")]
+async fn read_line<T: AsyncBufRead + Unpin + Send>(
+    stream: &mut T,
+    line: &mut String,
+) -> Result<usize, HttpError> {
+    line.clear();
+
+    let mut buf = Vec::new();
+    let read = (&mut *stream)
+        .take(MAX_LINE_LENGTH as u64 + 1)
+        .read_until(b'\n', &mut buf)
+        .await
+        .map_err(|err| http_error(400, &format!("failed to read the request: {err}")))?;
+
+    if buf.len() > MAX_LINE_LENGTH {
+        return Err(http_error(431, "line is too long"));
+    }
+    if read > 0 && !buf.ends_with(b"\n") {
+        return Err(http_error(400, "connection closed mid line"));
+    }
+
+    while buf.last().is_some_and(|c| *c == b'\n' || *c == b'\r') {
+        buf.pop();
+    }
+
+    line.push_str(
+        std::str::from_utf8(&buf).map_err(|_| http_error(400, "line is not valid utf-8"))?,
+    );
+
+    Ok(read)
+}
+
+#[cfg(feature = "wasm-ext")]
+fn parse_content_length(values: &[String]) -> Result<usize, HttpError> {
+    let mut lengths = values.iter().flat_map(|value| value.split(',')).map(|v| {
+        let v = v.trim();
+        match !v.is_empty() && v.bytes().all(|c| c.is_ascii_digit()) {
+            true => v
+                .parse::<usize>()
+                .map_err(|_| http_error(413, "the request body is too large")),
+            false => Err(http_error(400, "Content-Length using unexpected format")),
+        }
+    });
+
+    let length = lengths
+        .next()
+        .ok_or_else(|| http_error(400, "Content-Length using unexpected format"))??;
+
+    // repeated values are only acceptable when they agree
+    for other in lengths {
+        if other? != length {
+            return Err(http_error(400, "conflicting Content-Length values"));
+        }
+    }
+
+    Ok(length)
+}
+
+#[cfg(feature = "wasm-ext")]
+async fn read_chunked_body<T: AsyncBufRead + Unpin + Send>(
+    stream: &mut T,
+    max_body_size: usize,
+) -> Result<Vec<u8>, HttpError> {
+    let mut data = Vec::new();
+    let mut line = String::new();
+
+    loop {
+        if read_line(stream, &mut line).await? == 0 {
+            return Err(http_error(400, "connection closed before the last chunk"));
+        }
+
+        // chunk extensions are allowed after the size, we have no use for them
+        let size = line.split(';').next().unwrap_or_default().trim();
+        // `from_str_radix` also takes a leading sign, the size is hex digits only
+        if size.is_empty() || !size.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(http_error(400, "malformed chunk size"));
+        }
+        let size =
+            usize::from_str_radix(size, 16).map_err(|_| http_error(400, "malformed chunk size"))?;
+
+        if size == 0 {
+            break;
+        }
+        if size > max_body_size - data.len() {
+            return Err(http_error(413, "the request body is too large"));
+        }
 
-    Ok(req)
+        let start = data.len();
+        data.resize(start + size, 0);
+        stream
+            .read_exact(&mut data[start..])
+            .await
+            .map_err(|_| http_error(400, "connection closed mid chunk"))?;
+
+        if read_line(stream, &mut line).await? == 0 || !line.is_empty() {
+            return Err(http_error(
+                400,
+                "chunk data is not followed by a line break",
+            ));
+        }
+    }
+
+    // trailer fields are read and dropped, the request is complete once an empty line is read
+    for _ in 0..=MAX_HEADERS {
+        if read_line(stream, &mut line).await? == 0 {
+            return Err(http_error(
+                400,
+                "connection closed before the end of the trailers",
+            ));
+        }
+        if line.is_empty() {
+            return Ok(data);
+        }
+    }
+    Err(http_error(431, "too many trailer fields"))
+}
+
+#[cfg(feature = "wasm-ext")]
+fn http_error(status_code: u16, message: &str) -> HttpError {
+    HttpError {
+        status_code,
+        message: message.to_string(),
+    }
 }
 
 pub fn get_header(header_key: &str, headers: &[HeaderField]) -> Option<String> {
     headers
         .iter()
-        .find(|header| header.0.to_lowercase() == header_key.to_lowercase())
+        .find(|header| header.0.eq_ignore_ascii_case(header_key))
+        .map(|v| v.1.clone())
+}
+
+/// Returns the values of every header field with the given name, in the order they were received.
+pub fn get_header_values(header_key: &str, headers: &[HeaderField]) -> Vec<String> {
+    headers
+        .iter()
+        .filter(|header| header.0.eq_ignore_ascii_case(header_key))
         .map(|v| v.1.clone())
+        .collect()
+}
+
+#[cfg(feature = "wasm-ext")]
+#[tokio::test]
+async fn parse_request_headers_and_body() {
+    let raw = b"POST /procedure HTTP/1.1\r\n\
+        device-url: http://localhost:8080\r\n\
+        Accept: text/plain\r\n\
+        accept: application/json\r\n\
+        content-length: 5\r\n\
+        \r\n\
+        hello";
+
+    let req = parse_request(&raw[..]).await.unwrap().unwrap();
+    assert_eq!(req.method, "POST");
+    assert_eq!(req.path, "/procedure");
+    assert_eq!(req.data, b"hello");
+    assert!(req.keep_alive());
+
+    // header names are case-insensitive and values keep their colons
+    assert_eq!(
+        get_header(&Header::DeviceUrl.to_string(), &req.headers).unwrap(),
+        "http://localhost:8080"
+    );
+    assert_eq!(
+        get_header_values("ACCEPT", &req.headers),
+        vec!["text/plain", "application/json"]
+    );
+}
+
+#[cfg(feature = "wasm-ext")]
+#[tokio::test]
+async fn parse_request_chunked_and_keep_alive() {
+    let raw = b"POST /procedure HTTP/1.1\r\n\
+        Transfer-Encoding: chunked\r\n\
+        \r\n\
+        5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nTrailer: value\r\n\r\n\
+        DELETE /program HTTP/1.1\r\n\
+        Connection: close\r\n\
+        \r\n";
+
+    let mut stream = &raw[..];
+    let req = parse_request(&mut stream).await.unwrap().unwrap();
+    assert_eq!(req.data, b"hello, world");
+    assert!(req.keep_alive());
+
+    let req = parse_request(&mut stream).await.unwrap().unwrap();
+    assert_eq!(req.method, "DELETE");
+    assert!(!req.keep_alive());
+
+    // the client closed the connection
+    assert!(parse_request(&mut stream).await.unwrap().is_none());
+}
+
+#[cfg(feature = "wasm-ext")]
+#[tokio::test]
+async fn parse_request_rejects_malformed_input() {
+    async fn status(raw: &[u8]) -> u16 {
+        match parse_request_with_limit(raw, 8).await {
+            Err(HttpError { status_code, .. }) => status_code,
+            other => panic!("expected a http error, got {other:?}"),
+        }
+    }
+
+    assert_eq!(status(b"POST /procedure\r\n\r\n").await, 400);
+    assert_eq!(status(b"GET / HTTP/2.0\r\n\r\n").await, 505);
+    assert_eq!(status(b"GET / HTTP/1.1\r\nno colon\r\n\r\n").await, 400);
+    assert_eq!(status(b"POST / HTTP/1.1\r\n\r\n").await, 411);
+    assert_eq!(
+        status(b"POST / HTTP/1.1\r\nContent-Length: 1a\r\n\r\n").await,
+        400
+    );
+    assert_eq!(
+        status(b"POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 3\r\n\r\nabc").await,
+        400
+    );
+    assert_eq!(
+        status(b"POST / HTTP/1.1\r\nContent-Length: 9\r\n\r\n123456789").await,
+        413
+    );
+    assert_eq!(
+        status(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n9\r\n123456789\r\n0\r\n\r\n")
+            .await,
+        413
+    );
+    assert_eq!(
+        status(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n+3\r\nabc\r\n0\r\n\r\n")
+            .await,
+        400
+    );
+    let trailers = "Trailer: value\r\n".repeat(MAX_HEADERS + 1);
+    assert_eq!(
+        status(
+            format!("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n{trailers}\r\n")
+                .as_bytes()
+        )
+        .await,
+        431
+    );
+
+    // the body is cut short by the client
+    assert_eq!(
+        status(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nabc").await,
+        400
+    );
+}
+
+#[cfg(feature = "wasm-ext")]
+#[test]
+fn error_responses_carry_a_reason_phrase() {
+    use std::time::Duration;
+
+    let errors = [
+        Error::IO {
+            message: String::new(),
+            inner: None,
+        },
+        Error::Internal {
+            message: String::new(),
+            inner: None,
+        },
+        Error::Unauthorized {
+            message: String::new(),
+        },
+        Error::NotFound {
+            message: String::new(),
+        },
+        Error::Busy {
+            message: String::new(),
+        },
+        Error::RateLimited {
+            message: String::new(),
+            retry_after: Duration::from_secs(1),
+        },
+        Error::Timeout {
+            message: String::new(),
+        },
+        Error::ResourceExhausted {
+            message: String::new(),
+        },
+        Error::Integrity {
+            message: String::new(),
+        },
+        Error::Canister {
+            message: String::new(),
+            inner: None,
+        },
+    ];
+
+    for error in errors {
+        let status_code = error.status_code();
+        let response = Response::from(error);
+        let status_line = response.status_and_headers();
+        let status_line = status_line.lines().next().unwrap();
+        assert!(
+            !reason_phrase(status_code).is_empty(),
+            "no reason phrase for {status_code}"
+        );
+        assert_eq!(
+            status_line,
+            format!("HTTP/1.1 {status_code} {}", reason_phrase(status_code))
+        );
+    }
 }
@@ -7,7 +7,7 @@ use serde::{Deserialize, Serialize};
 /// consumer, but rather by the `harness` macro to generate the necessary code.
 ///
 /// Ok to access once it's present in the [`Program`](crate::program::Program) struct.
-#[derive(Deserialize, Default, Serialize, Clone, Debug, CandidType)]
+#[derive(Deserialize, Default, Serialize, Clone, Debug, PartialEq, Eq, CandidType)]
 pub struct Schema {
     pub version: String,
     pub program: String,
@@ -42,7 +42,7 @@ impl From<Schema> for IntermediateSchema {
 /// consumer, but rather by the `harness` macro to generate the necessary code.
 ///
 /// Ok to access once it's present in the [`Program`](crate::program::Program) struct.
-#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, CandidType)]
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq, Eq, CandidType)]
 pub struct Service {
     pub name: String,
     pub args: Vec<String>,
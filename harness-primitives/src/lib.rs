@@ -1,8 +1,11 @@
 //pub mod device;
+pub mod auth;
+mod engine;
 pub mod error;
 pub mod harness_os;
 pub mod http;
 pub mod internals;
+pub mod manifest;
 pub mod program;
 pub mod result;
 
@@ -0,0 +1,35 @@
+//! The manifest a program owner signs at build time, it is served by the canister next to the
+//! program so that devices can tell who published the program they pull.
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+
+use crate::error::{Error, Result};
+
+/// Describes a build of a program.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct ProgramManifest {
+    pub program_id: String,
+    pub version: String,
+    /// The hex encoded SHA-256 hash of the module.
+    pub sha256: String,
+}
+
+/// A manifest along with the ed25519 signature of its publisher.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct SignedManifest {
+    /// The JSON encoded [`ProgramManifest`], the signature covers these exact bytes.
+    pub manifest: Vec<u8>,
+    /// The ed25519 public key of the publisher.
+    pub public_key: Vec<u8>,
+    /// The ed25519 signature of the manifest.
+    pub signature: Vec<u8>,
+}
+
+impl SignedManifest {
+    /// Decodes the manifest, the signature is not checked.
+    pub fn manifest(&self) -> Result<ProgramManifest> {
+        serde_json::from_slice(&self.manifest).map_err(|err| Error::Integrity {
+            message: format!("the program manifest cannot be decoded: {err}"),
+        })
+    }
+}
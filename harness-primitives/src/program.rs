@@ -26,6 +26,77 @@ impl ProgramId {
     pub const fn new(program_id: String) -> Self {
         Self(program_id)
     }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// The resources a program may use on the device, unset limits are not enforced.
+#[derive(
+    Clone, Debug, Default, PartialEq, Eq, candid::CandidType, candid::Deserialize, serde::Serialize,
+)]
+pub struct ResourcePolicy {
+    /// The largest the linear memory of the program may grow to, in bytes.
+    pub max_memory_bytes: Option<u64>,
+    /// The largest the tables of the program may grow to, in elements.
+    pub max_table_elements: Option<u32>,
+    /// The fuel a single call may consume, roughly one unit per executed instruction.
+    pub fuel_per_call: Option<u64>,
+}
+
+impl ResourcePolicy {
+    /// Whether the policy sets no limit.
+    pub fn is_unlimited(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Combines the policies, keeping the tighter of each limit.
+    pub fn tightest(&self, other: &Self) -> Self {
+        Self {
+            max_memory_bytes: min(self.max_memory_bytes, other.max_memory_bytes),
+            max_table_elements: min(self.max_table_elements, other.max_table_elements),
+            fuel_per_call: min(self.fuel_per_call, other.fuel_per_call),
+        }
+    }
+}
+
+/// How often and how many calls at once the device serves for a program, unset limits are not
+/// enforced. Calls over the quota are turned away rather than queued.
+#[derive(
+    Clone, Debug, Default, PartialEq, Eq, candid::CandidType, candid::Deserialize, serde::Serialize,
+)]
+pub struct CallQuota {
+    /// The calls admitted per second on average, refilling a token bucket.
+    pub rate_per_sec: Option<u32>,
+    /// The calls admitted at once when the bucket is full, defaults to `rate_per_sec`.
+    pub burst: Option<u32>,
+    /// The calls that may run at the same time.
+    pub max_concurrent_calls: Option<u32>,
+}
+
+impl CallQuota {
+    /// Combines the quotas, keeping the tighter of each limit.
+    pub fn tightest(&self, other: &Self) -> Self {
+        Self {
+            rate_per_sec: min(self.rate_per_sec, other.rate_per_sec),
+            burst: min(self.burst, other.burst),
+            max_concurrent_calls: min(self.max_concurrent_calls, other.max_concurrent_calls),
+        }
+    }
+
+    /// Whether no limit is set.
+    pub fn is_unlimited(&self) -> bool {
+        self.rate_per_sec.is_none() && self.max_concurrent_calls.is_none()
+    }
+}
+
+/// The tighter of two optional limits, an unset limit is no limit.
+fn min<T: Ord + Copy>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
 }
 
 impl TryFrom<String> for ProgramId {
@@ -63,3 +134,52 @@ fn program_id_compatible_with_string() {
     let program_id = "Parsing str".parse::<ProgramId>().unwrap();
     _ = String::from(program_id);
 }
+
+#[test]
+fn resource_policy_keeps_the_tightest_limits() {
+    let node = ResourcePolicy {
+        max_memory_bytes: Some(1 << 20),
+        fuel_per_call: Some(1_000),
+        ..Default::default()
+    };
+    let program = ResourcePolicy {
+        max_memory_bytes: Some(1 << 16),
+        max_table_elements: Some(10),
+        fuel_per_call: Some(10_000),
+    };
+
+    assert_eq!(
+        node.tightest(&program),
+        ResourcePolicy {
+            max_memory_bytes: Some(1 << 16),
+            max_table_elements: Some(10),
+            fuel_per_call: Some(1_000),
+        }
+    );
+}
+
+#[test]
+fn call_quota_keeps_the_tightest_limits() {
+    let node = CallQuota {
+        rate_per_sec: Some(100),
+        max_concurrent_calls: Some(2),
+        ..Default::default()
+    };
+    let program = CallQuota {
+        rate_per_sec: Some(10),
+        burst: Some(20),
+        max_concurrent_calls: None,
+    };
+
+    let quota = node.tightest(&program);
+    assert_eq!(
+        quota,
+        CallQuota {
+            rate_per_sec: Some(10),
+            burst: Some(20),
+            max_concurrent_calls: Some(2),
+        }
+    );
+    assert!(!quota.is_unlimited());
+    assert!(CallQuota::default().is_unlimited());
+}